@@ -0,0 +1,300 @@
+//! Files sent over a direct connection between two clients, rather than through the server.
+//!
+//! The sender offers the file to the recipient over the chat connection with
+//! [`Command::DirectOffer`], see [`offer`]. The recipient listens for the sender with a
+//! [`DirectListener`] and accepts the offer with [`Command::DirectAccept`] giving the address it
+//! listens on and a random token. The sender then connects to that address and [`send`]s the file.
+//!
+//! On the direct connection the sender writes `FILE <id> <token>\r\n` followed by the contents of
+//! the file and then closes its side of the connection. Connections that don't give the token are
+//! dropped, so only the client the token was relayed to can send the file. Once the recipient has read the whole file it
+//! checks the SHA-256 hash against the hash in the offer and replies `OK\r\n`, or `BAD\r\n` if the
+//! hash doesn't match.
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use protocol::command::Command;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+/// The longest line written on a direct connection, anything longer isn't from a lanchat client.
+const MAX_LINE_LEN: u64 = 64;
+
+/// How long a connection has to identify the transfer it is for before it is dropped.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of random bytes in a token, which is written as hex.
+const TOKEN_LEN: usize = 16;
+
+/// Hashes the file at `path` and returns the offer to send to the server, the name offered is the
+/// name of the file without its directory.
+pub async fn offer(target: &str, path: &Path) -> io::Result<Command> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+
+    Ok(Command::DirectOffer {
+        target: target.to_owned(),
+        id: None,
+        size,
+        hash: hex(&hasher.finalize()),
+        name: name.to_owned(),
+    })
+}
+
+/// Sends a file over a direct connection to the recipient listening on `addr`, once the recipient
+/// has accepted the transfer with the given `id` and `token`. Returns an error if the recipient
+/// didn't receive the file intact.
+pub async fn send<R>(
+    addr: SocketAddr,
+    id: u64,
+    token: &str,
+    src: &mut R,
+) -> Result<(), TransferError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(format!("FILE {} {}\r\n", id, token).as_bytes())
+        .await?;
+    tokio::io::copy(src, &mut stream).await?;
+    stream.shutdown().await?;
+
+    let mut verdict = String::new();
+    BufReader::new(stream)
+        .take(MAX_LINE_LEN)
+        .read_line(&mut verdict)
+        .await?;
+    match verdict.as_str() {
+        "OK\r\n" => Ok(()),
+        _ => Err(TransferError::Rejected),
+    }
+}
+
+/// Listens for the sender of a file to connect.
+#[derive(Debug)]
+pub struct DirectListener {
+    listener: TcpListener,
+    token: String,
+}
+
+impl DirectListener {
+    /// Listens on a port chosen by the OS. The server only accepts a listening address with the
+    /// same IP as the client's connection to the server, which is the local address of that
+    /// connection.
+    pub async fn bind(ip: IpAddr) -> io::Result<DirectListener> {
+        let listener = TcpListener::bind((ip, 0)).await?;
+        let mut token = [0; TOKEN_LEN];
+        getrandom::getrandom(&mut token).map_err(io::Error::from)?;
+        Ok(DirectListener {
+            listener,
+            token: hex(&token),
+        })
+    }
+
+    /// The address to give in [`Command::DirectAccept`].
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The token to give in [`Command::DirectAccept`], which the sender must give when it
+    /// connects.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Waits for the sender to connect and writes the file to `dest`, checking that it has the
+    /// offered `size` and `hash`. Connections for other transfers or without the token are ignored. There is no limit
+    /// on how long this waits for the sender, use [`tokio::time::timeout`] to give up on a
+    /// sender that never connects.
+    pub async fn receive<W>(
+        self,
+        id: u64,
+        size: u64,
+        hash: &str,
+        dest: &mut W,
+    ) -> Result<(), TransferError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let expected_header = format!("FILE {} {}\r\n", id, self.token);
+        let mut stream = loop {
+            let (stream, _) = self.listener.accept().await?;
+            let mut stream = BufReader::new(stream);
+            let mut header = String::new();
+            // Connections from anything other than the sender are dropped.
+            let mut limited = (&mut stream).take(MAX_LINE_LEN);
+            if let Ok(Ok(_)) = timeout(HEADER_TIMEOUT, limited.read_line(&mut header)).await {
+                if header == expected_header {
+                    break stream;
+                }
+            }
+        };
+
+        // Read one byte more than expected so that a file that is too large is detected.
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        let mut buf = vec![0; 64 * 1024];
+        let mut file = (&mut stream).take(size + 1);
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            dest.write_all(&buf[..n]).await?;
+            received += n as u64;
+        }
+        dest.flush().await?;
+
+        let result = if received != size {
+            Err(TransferError::SizeMismatch {
+                expected: size,
+                received,
+            })
+        } else if hex(&hasher.finalize()) != hash {
+            Err(TransferError::HashMismatch)
+        } else {
+            Ok(())
+        };
+        let verdict = if result.is_ok() { "OK\r\n" } else { "BAD\r\n" };
+        stream.get_mut().write_all(verdict.as_bytes()).await?;
+        result
+    }
+}
+
+/// Writes bytes as lowercase hex, the format of the hashes in file offers.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    /// The recipient received a different number of bytes to the size in the offer.
+    SizeMismatch {
+        expected: u64,
+        received: u64,
+    },
+    /// The hash of the file received doesn't match the hash in the offer.
+    HashMismatch,
+    /// The recipient didn't receive the file intact.
+    Rejected,
+    Io(io::Error),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use TransferError::*;
+        match self {
+            SizeMismatch { expected, received } => write!(
+                f,
+                "Expected a file of {} bytes but received {} bytes",
+                expected, received
+            ),
+            HashMismatch => f.write_str("The file received doesn't match the hash offered"),
+            Rejected => f.write_str("The recipient didn't receive the file intact"),
+            Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> TransferError {
+        TransferError::Io(e)
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn contents() -> Vec<u8> {
+        (0..200_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn file_is_sent_directly() {
+        let path = std::env::temp_dir().join(format!("lanchat-direct-{}.bin", std::process::id()));
+        tokio::fs::write(&path, contents()).await.unwrap();
+        let (size, hash) = match offer("bob", &path).await.unwrap() {
+            Command::DirectOffer {
+                size, hash, name, ..
+            } => {
+                assert_eq!(path.file_name().unwrap().to_str().unwrap(), name);
+                (size, hash)
+            }
+            other => panic!("unexpected offer: {:?}", other),
+        };
+
+        let listener = DirectListener::bind(LOCALHOST).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = listener.token().to_owned();
+        let sender = tokio::spawn(async move {
+            // Connections for another transfer or without the token are ignored.
+            let mut other = TcpStream::connect(addr).await.unwrap();
+            let header = format!("FILE 6 {}\r\nnot this one", token);
+            other.write_all(header.as_bytes()).await.unwrap();
+            let mut guess = TcpStream::connect(addr).await.unwrap();
+            guess.write_all(b"FILE 7 0000\r\nnor this").await.unwrap();
+
+            let mut file = File::open(&path).await.unwrap();
+            let result = send(addr, 7, &token, &mut file).await;
+            tokio::fs::remove_file(&path).await.unwrap();
+            result
+        });
+
+        let mut received = Vec::new();
+        listener
+            .receive(7, size, &hash, &mut received)
+            .await
+            .unwrap();
+        sender.await.unwrap().unwrap();
+        assert_eq!(contents(), received);
+    }
+
+    #[tokio::test]
+    async fn file_that_doesnt_match_the_offer_is_rejected() {
+        let listener = DirectListener::bind(LOCALHOST).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let token = listener.token().to_owned();
+        let sender =
+            tokio::spawn(async move { send(addr, 1, &token, &mut &b"tampered"[..]).await });
+
+        // sha256("original")
+        let hash = "0682c5f2076f099c34cfdd15a9e063849ed437a49677e6fcc5b4198c76575be5";
+        let result = listener.receive(1, 8, hash, &mut Vec::new()).await;
+        assert!(matches!(result, Err(TransferError::HashMismatch)));
+        assert!(matches!(
+            sender.await.unwrap(),
+            Err(TransferError::Rejected)
+        ));
+    }
+}
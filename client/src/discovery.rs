@@ -0,0 +1,134 @@
+//! Finding servers on the local network, see [`protocol::discovery`].
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use protocol::{
+    command::Command,
+    discovery::{DEFAULT_PORT, MULTICAST_ADDR},
+    message::LanChatMessage,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+/// A server that replied to a discovery request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    /// The address to connect to, the address the reply came from with the port the server
+    /// announced.
+    pub addr: SocketAddr,
+    pub name: String,
+    /// Whether the server expects connections to use TLS.
+    pub tls: bool,
+    /// The number of registered users.
+    pub users: usize,
+}
+
+/// Lists the servers on the local network that reply within `wait`, the request is broadcast and
+/// sent to the discovery multicast group on the default port.
+pub async fn discover(wait: Duration) -> io::Result<Vec<DiscoveredServer>> {
+    let targets = [
+        SocketAddr::from((Ipv4Addr::BROADCAST, DEFAULT_PORT)),
+        SocketAddr::from((MULTICAST_ADDR, DEFAULT_PORT)),
+    ];
+    discover_at(&targets, wait).await
+}
+
+/// Lists the servers that reply within `wait` to a discovery request sent to each of the given
+/// IPv4 addresses. Each server is only listed once, however many of the addresses reach it.
+pub async fn discover_at(
+    targets: &[SocketAddr],
+    wait: Duration,
+) -> io::Result<Vec<DiscoveredServer>> {
+    let deadline = Instant::now() + wait;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+
+    // Some targets may be unreachable, for example when there is no route for multicast, which
+    // is only an error if no request could be sent at all.
+    let request = LanChatMessage {
+        prefix: None,
+        command: Command::Discover,
+    }
+    .to_string();
+    let mut sent = Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "No addresses to send discovery requests to",
+    ));
+    for target in targets {
+        match socket.send_to(request.as_bytes(), target).await {
+            Ok(_) => sent = Ok(()),
+            Err(e) if sent.is_err() => sent = Err(e),
+            Err(_) => {}
+        }
+    }
+    sent?;
+
+    let mut servers = Vec::new();
+    let mut buf = [0; 512];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = match received {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let reply = std::str::from_utf8(&buf[..n])
+            .ok()
+            .and_then(|reply| reply.parse::<LanChatMessage>().ok());
+        if let Some(LanChatMessage {
+            command:
+                Command::Announce {
+                    name,
+                    port,
+                    tls,
+                    users,
+                },
+            ..
+        }) = reply
+        {
+            let addr = SocketAddr::new(from.ip(), port);
+            if !servers.iter().any(|s: &DiscoveredServer| s.addr == addr) {
+                servers.push(DiscoveredServer {
+                    addr,
+                    name,
+                    tls,
+                    users,
+                });
+            }
+        }
+    }
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn servers_are_listed_once() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (_, from) = responder.recv_from(&mut buf).await.unwrap();
+            let announce = "ANNOUNCE lanchat.local 3000 plain 4\r\n";
+            for reply in [announce, "not a reply", announce] {
+                responder.send_to(reply.as_bytes(), from).await.unwrap();
+            }
+        });
+
+        let servers = discover_at(&[addr], Duration::from_millis(300))
+            .await
+            .unwrap();
+        let expected = DiscoveredServer {
+            addr: "127.0.0.1:3000".parse().unwrap(),
+            name: "lanchat.local".to_owned(),
+            tls: false,
+            users: 4,
+        };
+        assert_eq!(vec![expected], servers);
+    }
+}
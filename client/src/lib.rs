@@ -0,0 +1,4 @@
+//! Building blocks for lanchat clients.
+pub mod direct;
+pub mod discovery;
+pub mod mdns;
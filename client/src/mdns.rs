@@ -0,0 +1,101 @@
+//! Finding servers advertised as DNS-SD services over mDNS, see [`protocol::discovery`].
+use std::{net::SocketAddr, time::Duration};
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use protocol::discovery::{SERVICE_TYPE, TXT_CAPABILITIES, TXT_NAME, TXT_TLS, TXT_VERSION};
+use tokio::time::{timeout_at, Instant};
+
+/// A server advertised over mDNS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdvertisedServer {
+    pub addr: SocketAddr,
+    pub name: String,
+    /// The version of the server, if it was advertised.
+    pub version: Option<String>,
+    /// The optional protocol features the server supports.
+    pub capabilities: Vec<String>,
+    /// Whether the server expects connections to use TLS.
+    pub tls: bool,
+}
+
+/// Lists the servers advertised on the local network, including those on this machine, that are
+/// found within `wait`.
+pub async fn browse(wait: Duration) -> mdns_sd::Result<Vec<AdvertisedServer>> {
+    let deadline = Instant::now() + wait;
+    let daemon = ServiceDaemon::new()?;
+    daemon.enable_interface(IfKind::LoopbackV4)?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    let mut servers = Vec::new();
+    while let Ok(Ok(event)) = timeout_at(deadline, events.recv_async()).await {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let server = advertised_server(&info);
+            if let Some(server) = server.filter(|server| !servers.contains(server)) {
+                servers.push(server);
+            }
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(servers)
+}
+
+/// Reads the details of a server from a resolved service, preferring an IPv4 address. Returns
+/// `None` if the service has no addresses.
+fn advertised_server(info: &ServiceInfo) -> Option<AdvertisedServer> {
+    let ip = info
+        .get_addresses()
+        .iter()
+        .min_by_key(|ip| ip.is_ipv6())
+        .copied()?;
+    let name = match info.get_property_val_str(TXT_NAME) {
+        Some(name) => name.to_owned(),
+        None => info
+            .get_fullname()
+            .trim_end_matches(SERVICE_TYPE)
+            .trim_end_matches('.')
+            .to_owned(),
+    };
+    Some(AdvertisedServer {
+        addr: SocketAddr::new(ip, info.get_port()),
+        name,
+        version: info.get_property_val_str(TXT_VERSION).map(str::to_owned),
+        capabilities: info
+            .get_property_val_str(TXT_CAPABILITIES)
+            .map(|caps| {
+                caps.split(',')
+                    .filter(|cap| !cap.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        tls: info.get_property_val_str(TXT_TLS) == Some("true"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertised_server_is_read_from_txt_records() {
+        let properties = [(TXT_NAME, "chat.example.lan"), (TXT_CAPABILITIES, "typing")];
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "chat",
+            "chat.local.",
+            "192.168.0.2",
+            3000,
+            &properties[..],
+        )
+        .unwrap();
+
+        let expected = AdvertisedServer {
+            addr: "192.168.0.2:3000".parse().unwrap(),
+            name: "chat.example.lan".to_owned(),
+            version: None,
+            capabilities: vec!["typing".to_owned()],
+            tls: false,
+        };
+        assert_eq!(Some(expected), advertised_server(&info));
+    }
+}
@@ -156,7 +156,7 @@ impl std::error::Error for LanChatCodecError {}
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::command::Command;
+    use crate::command::{Command, MsgTags};
     use crate::message::Prefix;
     use bytes::BytesMut;
 
@@ -176,7 +176,10 @@ mod tests {
 
         let expected = LanChatMessage {
             prefix: None,
-            command: Command::Msg("Hi!".to_owned()),
+            command: Command::Msg {
+                text: "Hi!".to_owned(),
+                tags: MsgTags::default(),
+            },
         };
 
         assert_eq!(expected, codec.decode(buf).unwrap().unwrap());
@@ -187,10 +190,11 @@ mod tests {
 
         buf.put_slice(b":hello???\r\n");
         let expected = LanChatMessage {
-            prefix: Some(Prefix {
-                nick: "olly".to_owned(),
-            }),
-            command: Command::Msg("hello???".to_owned()),
+            prefix: Some(Prefix::user("olly")),
+            command: Command::Msg {
+                text: "hello???".to_owned(),
+                tags: MsgTags::default(),
+            },
         };
         assert_eq!(expected, codec.decode(buf).unwrap().unwrap());
     }
@@ -214,7 +218,10 @@ mod tests {
         buf.put_slice(b"\r\nMSG :ok!\r\n");
         let expected = LanChatMessage {
             prefix: None,
-            command: Command::Msg("ok!".to_owned()),
+            command: Command::Msg {
+                text: "ok!".to_owned(),
+                tags: MsgTags::default(),
+            },
         };
         assert_eq!(expected, codec.decode(buf).unwrap().unwrap());
 
@@ -226,7 +233,10 @@ mod tests {
         buf.put_slice(b"MSG :valid!\r\n");
         let expected = LanChatMessage {
             prefix: None,
-            command: Command::Msg("valid!".to_owned()),
+            command: Command::Msg {
+                text: "valid!".to_owned(),
+                tags: MsgTags::default(),
+            },
         };
         assert_eq!(expected, codec.decode(buf).unwrap().unwrap());
     }
@@ -1,4 +1,7 @@
 use std::fmt;
+use std::net::SocketAddr;
+
+use crate::{file, nick};
 
 use nom::{
     bytes::complete::{take, take_while},
@@ -9,12 +12,275 @@ use nom::{
     IResult,
 };
 
-/// Issue commands from the client to the server
+/// Issue commands from the client to the server, or replies from the server to the client
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Nick(String),
-    Msg(String),
+    /// Send a message to everyone connected to the server.
+    Msg {
+        text: String,
+        tags: MsgTags,
+    },
+    /// Send a message to a single user identified by their nick, or to the members of a channel
+    /// when the target starts with `#`.
+    PrivMsg {
+        target: String,
+        text: String,
+        tags: MsgTags,
+    },
+    /// Informational text routed like a message, to everyone when there is no target, to a
+    /// channel or to a single user. Clients must never automatically respond to a notice, which
+    /// avoids loops between bots.
+    Notice {
+        target: Option<String>,
+        text: String,
+    },
+    /// An emote such as `/me waves`, where `text` describes what the sender is doing. Routed like
+    /// a message, to everyone when there is no target, to a channel or to a single user.
+    Action {
+        target: Option<String>,
+        text: String,
+    },
     Quit,
+    /// Join the given channel, creating it if it doesn't exist.
+    Join(String),
+    /// Leave the given channel.
+    Part(String),
+    /// Request the topic of the given channel, or set it when `text` is present. An empty `text`
+    /// clears the topic.
+    Topic {
+        channel: String,
+        text: Option<String>,
+    },
+    /// Sent in reply to [`Command::Topic`] and on joining a channel, `set_at` is the time at which
+    /// the topic was set as seconds since the unix epoch.
+    TopicReply {
+        channel: String,
+        nick: String,
+        set_at: u64,
+        text: String,
+    },
+    /// Sent in place of [`Command::TopicReply`] when a channel has no topic.
+    NoTopic(String),
+    /// Request the nicks of all connected users, or only those in the given channel.
+    Names(Option<String>),
+    /// Request the nicks of all connected users along with connection metadata.
+    Who,
+    /// A single user sent in reply to [`Command::Names`].
+    NamesReply {
+        nick: String,
+        away: bool,
+    },
+    /// Marks the end of a sequence of [`Command::NamesReply`].
+    EndOfNames,
+    /// A single user sent in reply to [`Command::Who`], `idle` is the number of seconds since the
+    /// user last sent a message.
+    WhoReply {
+        nick: String,
+        addr: SocketAddr,
+        idle: u64,
+        away: bool,
+    },
+    /// Marks the end of a sequence of [`Command::WhoReply`].
+    EndOfWho,
+    /// Request the details of the user with the given nick.
+    Whois(String),
+    /// Sent in reply to [`Command::Whois`], `connected` is the time at which the user connected
+    /// as seconds since the unix epoch and `idle` is the number of seconds since the user last
+    /// sent a message.
+    WhoisUser {
+        nick: String,
+        connected: u64,
+        idle: u64,
+    },
+    /// The channels a user is in, sent in reply to [`Command::Whois`].
+    WhoisChannels {
+        nick: String,
+        channels: Vec<String>,
+    },
+    /// The address a user is connected from, sent in reply to [`Command::Whois`] to operators
+    /// only.
+    WhoisAddr {
+        nick: String,
+        addr: SocketAddr,
+    },
+    /// Marks the end of a reply to [`Command::Whois`].
+    EndOfWhois(String),
+    /// Mark the user as away with the given message, or as no longer away if there is no message.
+    /// The server relays the command to all clients to notify them of the change.
+    Away(Option<String>),
+    /// Sent to a client when it messages a user who is away, and in reply to [`Command::Whois`].
+    AwayReply {
+        nick: String,
+        msg: String,
+    },
+    /// Authenticate as a server operator.
+    Oper {
+        name: String,
+        password: String,
+    },
+    /// Sent in reply to a successful [`Command::Oper`].
+    YoureOper,
+    /// Remove a user from a channel, operators only.
+    Kick {
+        channel: String,
+        nick: String,
+        reason: Option<String>,
+    },
+    /// Disconnect a user from the server, operators only.
+    Kill {
+        nick: String,
+        reason: Option<String>,
+    },
+    /// Prevent a user from sending messages, operators only.
+    Mute(String),
+    /// Allow a muted user to send messages again, operators only.
+    Unmute(String),
+    /// Ban clients matching `mask` from the server, operators only. The mask is an IP address, a
+    /// CIDR range or a nick glob. The ban is permanent unless a duration in seconds is given.
+    Ban {
+        mask: String,
+        duration: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Remove the ban with the given mask, operators only.
+    Unban(String),
+    /// Request the list of active bans, operators only.
+    BanList,
+    /// A single ban sent in reply to [`Command::BanList`], `expires` is the time at which the ban
+    /// expires as seconds since the unix epoch or 0 if the ban is permanent.
+    BanReply {
+        mask: String,
+        set_by: String,
+        expires: u64,
+        reason: String,
+    },
+    /// Marks the end of a sequence of [`Command::BanReply`].
+    EndOfBanList,
+    /// Request the server's message of the day.
+    Motd,
+    /// A single line of the message of the day, sent after registering and in reply to
+    /// [`Command::Motd`].
+    MotdReply(String),
+    /// Marks the end of a sequence of [`Command::MotdReply`].
+    EndOfMotd,
+    /// Sent in place of [`Command::MotdReply`] when the server has no message of the day.
+    NoMotd,
+    /// Request the messages in the thread containing the message with the given id. The server
+    /// replies with the messages, oldest first, followed by [`Command::EndOfThread`].
+    Thread(u64),
+    /// Marks the end of the messages sent in reply to [`Command::Thread`].
+    EndOfThread(u64),
+    /// Request the recent messages sent to everyone, or those sent to the given channel or
+    /// between the client and the given nick. The server replies with the messages, oldest first,
+    /// followed by [`Command::EndOfHistory`].
+    History(Option<String>),
+    /// Marks the end of the messages sent in reply to [`Command::History`].
+    EndOfHistory,
+    /// Replace the text of the message with the given id, only the author or an operator can edit
+    /// a message.
+    Edit {
+        id: u64,
+        text: String,
+    },
+    /// Delete the message with the given id, only the author or an operator can delete a message.
+    Delete(u64),
+    /// React to the message with the given id, see [`is_valid_reaction`] for what can be used as
+    /// a reaction.
+    React {
+        id: u64,
+        emoji: String,
+    },
+    /// Remove a reaction from the message with the given id.
+    Unreact {
+        id: u64,
+        emoji: String,
+    },
+    /// Request optional protocol features, the server replies with the features from the request
+    /// that it supports. See [`CAP_TYPING`].
+    Cap(Vec<String>),
+    /// Tells the members of a channel, a single user, or everyone when there is no target, that
+    /// the sender has started or stopped typing. Only sent to clients that have requested
+    /// [`CAP_TYPING`].
+    Typing {
+        target: Option<String>,
+        typing: bool,
+    },
+    /// Offer to send a file to a single user, see the [`file`] module. The server gives the
+    /// transfer an `id` when relaying the offer, which is also echoed back to the sender.
+    ///
+    /// [`file`]: crate::file
+    FileOffer {
+        target: String,
+        id: Option<u64>,
+        size: u64,
+        hash: String,
+        name: String,
+    },
+    /// Accept the offered file with the given transfer id, the sender then starts sending chunks.
+    FileAccept(u64),
+    /// Decline the offered file with the given transfer id.
+    FileReject(u64),
+    /// Part of an accepted file starting at `offset`, `data` is encoded with base64.
+    FileChunk {
+        id: u64,
+        offset: u64,
+        data: String,
+    },
+    /// Sent after the last chunk of a file, with the SHA-256 hash of the whole file.
+    FileDone {
+        id: u64,
+        hash: String,
+    },
+    /// Abandon a file transfer, sent by either the sender or the recipient. The server also sends
+    /// it when the other side disconnects.
+    FileCancel(u64),
+    /// Offer to send a file to a single user over a direct connection rather than through the
+    /// server. The server gives the transfer an `id` as for [`Command::FileOffer`].
+    DirectOffer {
+        target: String,
+        id: Option<u64>,
+        size: u64,
+        hash: String,
+        name: String,
+    },
+    /// Accept the file offered with [`Command::DirectOffer`], the sender connects to `addr` to
+    /// send the file and gives `token`, chosen at random by the recipient, so that the recipient
+    /// knows the connection is from the sender. The offer can be declined with
+    /// [`Command::FileReject`].
+    DirectAccept {
+        id: u64,
+        addr: SocketAddr,
+        token: String,
+    },
+    /// Sent by a client in a UDP datagram to find servers on the local network, see the
+    /// [`discovery`] module.
+    ///
+    /// [`discovery`]: crate::discovery
+    Discover,
+    /// Sent by a server in reply to [`Command::Discover`], describing the server. `port` is the
+    /// TCP port that clients connect to and `users` is the number of registered users.
+    Announce {
+        name: String,
+        port: u16,
+        tls: bool,
+        users: usize,
+    },
+    /// Sent by each side of a link between two servers to authenticate with the other, `name` is
+    /// the server name of the sender and `password` is the password configured for the link.
+    Server {
+        name: String,
+        password: String,
+    },
+    /// Sent over a link between servers to make a user known to the other side, with the prefix
+    /// of the server the user is connected to. `registered` is when the user connected to that
+    /// server as seconds since the unix epoch, which decides nick collisions.
+    Introduce {
+        nick: String,
+        registered: u64,
+    },
+    /// Sent from the server to a client when a command could not be processed.
+    ErrorReply(String),
 }
 
 impl TryFrom<(&str, Params<'_>)> for Command {
@@ -26,30 +292,717 @@ impl TryFrom<(&str, Params<'_>)> for Command {
         match command {
             "NICK" => {
                 if middle.len() == 1 && trailing.is_none() {
+                    if !nick::is_valid(middle[0]) {
+                        return Err(format!("Invalid nickname: {}", middle[0]).into());
+                    }
                     Ok(Command::Nick(middle[0].to_owned()))
                 } else {
                     Err("Incorrect params for command: NICK".into())
                 }
             }
-            "MSG" => match (middle.len(), trailing) {
-                (0, Some(msg)) => Ok(Command::Msg(msg.to_owned())),
-                _ => Err("Incorrect params for command: MSG".into()),
+            "MSG" => {
+                let text = trailing
+                    .ok_or("Incorrect params for command: MSG")?
+                    .to_owned();
+                // Tags always contain a `=`, which nicks and channel names don't.
+                match middle.split_first() {
+                    Some((target, tags)) if !target.contains('=') => Ok(Command::PrivMsg {
+                        target: (*target).to_owned(),
+                        text,
+                        tags: MsgTags::parse(tags)?,
+                    }),
+                    _ => Ok(Command::Msg {
+                        text,
+                        tags: MsgTags::parse(&middle)?,
+                    }),
+                }
+            }
+            "NOTICE" => match (middle.len(), trailing) {
+                (0, Some(text)) => Ok(Command::Notice {
+                    target: None,
+                    text: text.to_owned(),
+                }),
+                (1, Some(text)) => Ok(Command::Notice {
+                    target: Some(middle[0].to_owned()),
+                    text: text.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: NOTICE".into()),
+            },
+            "ACTION" => match (middle.len(), trailing) {
+                (0, Some(text)) => Ok(Command::Action {
+                    target: None,
+                    text: text.to_owned(),
+                }),
+                (1, Some(text)) => Ok(Command::Action {
+                    target: Some(middle[0].to_owned()),
+                    text: text.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: ACTION".into()),
             },
             "QUIT" => Ok(Command::Quit),
+            "JOIN" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Join(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: JOIN".into()),
+            },
+            "PART" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Part(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: PART".into()),
+            },
+            "TOPIC" => match (middle.len(), trailing) {
+                (1, text) => Ok(Command::Topic {
+                    channel: middle[0].to_owned(),
+                    text: text.map(|text| text.to_owned()),
+                }),
+                _ => Err("Incorrect params for command: TOPIC".into()),
+            },
+            "TOPICREPLY" => match (middle.len(), trailing) {
+                (3, Some(text)) => Ok(Command::TopicReply {
+                    channel: middle[0].to_owned(),
+                    nick: middle[1].to_owned(),
+                    set_at: middle[2].parse()?,
+                    text: text.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: TOPICREPLY".into()),
+            },
+            "NOTOPIC" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::NoTopic(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: NOTOPIC".into()),
+            },
+            "NAMES" => match (middle.len(), trailing) {
+                (0, None) => Ok(Command::Names(None)),
+                (1, None) => Ok(Command::Names(Some(middle[0].to_owned()))),
+                _ => Err("Incorrect params for command: NAMES".into()),
+            },
+            "WHO" => match (middle.len(), trailing) {
+                (0, None) => Ok(Command::Who),
+                _ => Err("Incorrect params for command: WHO".into()),
+            },
+            "NAMREPLY" => match (middle.len(), trailing) {
+                (2, None) => Ok(Command::NamesReply {
+                    nick: middle[0].to_owned(),
+                    away: parse_away_flag(middle[1])?,
+                }),
+                _ => Err("Incorrect params for command: NAMREPLY".into()),
+            },
+            "ENDOFNAMES" => Ok(Command::EndOfNames),
+            "WHOREPLY" => match (middle.len(), trailing) {
+                (4, None) => Ok(Command::WhoReply {
+                    nick: middle[0].to_owned(),
+                    addr: middle[1].parse()?,
+                    idle: middle[2].parse()?,
+                    away: parse_away_flag(middle[3])?,
+                }),
+                _ => Err("Incorrect params for command: WHOREPLY".into()),
+            },
+            "ENDOFWHO" => Ok(Command::EndOfWho),
+            "WHOIS" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Whois(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: WHOIS".into()),
+            },
+            "WHOISUSER" => match (middle.len(), trailing) {
+                (3, None) => Ok(Command::WhoisUser {
+                    nick: middle[0].to_owned(),
+                    connected: middle[1].parse()?,
+                    idle: middle[2].parse()?,
+                }),
+                _ => Err("Incorrect params for command: WHOISUSER".into()),
+            },
+            "WHOISCHANNELS" => match (middle.len(), trailing) {
+                (1, Some(channels)) => Ok(Command::WhoisChannels {
+                    nick: middle[0].to_owned(),
+                    channels: channels.split_whitespace().map(|c| c.to_owned()).collect(),
+                }),
+                _ => Err("Incorrect params for command: WHOISCHANNELS".into()),
+            },
+            "WHOISADDR" => match (middle.len(), trailing) {
+                (2, None) => Ok(Command::WhoisAddr {
+                    nick: middle[0].to_owned(),
+                    addr: middle[1].parse()?,
+                }),
+                _ => Err("Incorrect params for command: WHOISADDR".into()),
+            },
+            "ENDOFWHOIS" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::EndOfWhois(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: ENDOFWHOIS".into()),
+            },
+            "AWAY" => match (middle.len(), trailing) {
+                (0, Some(msg)) if !msg.is_empty() => Ok(Command::Away(Some(msg.to_owned()))),
+                (0, _) => Ok(Command::Away(None)),
+                _ => Err("Incorrect params for command: AWAY".into()),
+            },
+            "AWAYREPLY" => match (middle.len(), trailing) {
+                (1, Some(msg)) => Ok(Command::AwayReply {
+                    nick: middle[0].to_owned(),
+                    msg: msg.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: AWAYREPLY".into()),
+            },
+            "OPER" => match (middle.len(), trailing) {
+                (2, None) => Ok(Command::Oper {
+                    name: middle[0].to_owned(),
+                    password: middle[1].to_owned(),
+                }),
+                _ => Err("Incorrect params for command: OPER".into()),
+            },
+            "YOUREOPER" => Ok(Command::YoureOper),
+            "KICK" => match (middle.len(), trailing) {
+                (2, reason) => Ok(Command::Kick {
+                    channel: middle[0].to_owned(),
+                    nick: middle[1].to_owned(),
+                    reason: reason.map(|reason| reason.to_owned()),
+                }),
+                _ => Err("Incorrect params for command: KICK".into()),
+            },
+            "KILL" => match (middle.len(), trailing) {
+                (1, reason) => Ok(Command::Kill {
+                    nick: middle[0].to_owned(),
+                    reason: reason.map(|reason| reason.to_owned()),
+                }),
+                _ => Err("Incorrect params for command: KILL".into()),
+            },
+            "MUTE" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Mute(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: MUTE".into()),
+            },
+            "UNMUTE" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Unmute(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: UNMUTE".into()),
+            },
+            "BAN" => match (middle.len(), trailing) {
+                (1, reason) => Ok(Command::Ban {
+                    mask: middle[0].to_owned(),
+                    duration: None,
+                    reason: reason.map(|reason| reason.to_owned()),
+                }),
+                (2, reason) => Ok(Command::Ban {
+                    mask: middle[0].to_owned(),
+                    duration: Some(middle[1].parse()?),
+                    reason: reason.map(|reason| reason.to_owned()),
+                }),
+                _ => Err("Incorrect params for command: BAN".into()),
+            },
+            "UNBAN" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Unban(middle[0].to_owned())),
+                _ => Err("Incorrect params for command: UNBAN".into()),
+            },
+            "BANLIST" => Ok(Command::BanList),
+            "BANREPLY" => match (middle.len(), trailing) {
+                (3, Some(reason)) => Ok(Command::BanReply {
+                    mask: middle[0].to_owned(),
+                    set_by: middle[1].to_owned(),
+                    expires: middle[2].parse()?,
+                    reason: reason.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: BANREPLY".into()),
+            },
+            "ENDOFBANLIST" => Ok(Command::EndOfBanList),
+            "MOTD" => Ok(Command::Motd),
+            "MOTDREPLY" => match (middle.len(), trailing) {
+                (0, Some(line)) => Ok(Command::MotdReply(line.to_owned())),
+                _ => Err("Incorrect params for command: MOTDREPLY".into()),
+            },
+            "ENDOFMOTD" => Ok(Command::EndOfMotd),
+            "NOMOTD" => Ok(Command::NoMotd),
+            "THREAD" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Thread(middle[0].parse()?)),
+                _ => Err("Incorrect params for command: THREAD".into()),
+            },
+            "ENDOFTHREAD" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::EndOfThread(middle[0].parse()?)),
+                _ => Err("Incorrect params for command: ENDOFTHREAD".into()),
+            },
+            "HISTORY" => match (middle.len(), trailing) {
+                (0, None) => Ok(Command::History(None)),
+                (1, None) => Ok(Command::History(Some(middle[0].to_owned()))),
+                _ => Err("Incorrect params for command: HISTORY".into()),
+            },
+            "ENDOFHISTORY" => Ok(Command::EndOfHistory),
+            "EDIT" => match (middle.len(), trailing) {
+                (1, Some(text)) => Ok(Command::Edit {
+                    id: middle[0].parse()?,
+                    text: text.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: EDIT".into()),
+            },
+            "DELETE" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::Delete(middle[0].parse()?)),
+                _ => Err("Incorrect params for command: DELETE".into()),
+            },
+            "REACT" => match (middle.len(), trailing) {
+                (2, None) => Ok(Command::React {
+                    id: middle[0].parse()?,
+                    emoji: middle[1].to_owned(),
+                }),
+                _ => Err("Incorrect params for command: REACT".into()),
+            },
+            "CAP" => match (middle.len(), trailing) {
+                (0, Some(caps)) => Ok(Command::Cap(
+                    caps.split_whitespace().map(str::to_owned).collect(),
+                )),
+                _ => Err("Incorrect params for command: CAP".into()),
+            },
+            "TYPING" => {
+                let (target, state) = match (middle.len(), trailing) {
+                    (1, None) => (None, middle[0]),
+                    (2, None) => (Some(middle[0].to_owned()), middle[1]),
+                    _ => return Err("Incorrect params for command: TYPING".into()),
+                };
+                Ok(Command::Typing {
+                    target,
+                    typing: match state {
+                        "start" => true,
+                        "stop" => false,
+                        other => return Err(format!("Unrecognized typing state: {}", other).into()),
+                    },
+                })
+            }
+            "UNREACT" => match (middle.len(), trailing) {
+                (2, None) => Ok(Command::Unreact {
+                    id: middle[0].parse()?,
+                    emoji: middle[1].to_owned(),
+                }),
+                _ => Err("Incorrect params for command: UNREACT".into()),
+            },
+            "FILEOFFER" | "DIRECTOFFER" => {
+                let (target, id, size, hash) = match (middle.len(), trailing) {
+                    (3, Some(_)) => (middle[0], None, middle[1], middle[2]),
+                    (4, Some(_)) => (middle[0], Some(middle[1].parse()?), middle[2], middle[3]),
+                    _ => return Err(format!("Incorrect params for command: {}", command).into()),
+                };
+                let name = trailing.unwrap_or_default();
+                if !file::is_valid_hash(hash) {
+                    return Err(format!("Invalid file hash: {}", hash).into());
+                }
+                if !file::is_valid_name(name) {
+                    return Err(format!("Invalid file name: {}", name).into());
+                }
+                let (target, size, hash, name) = (
+                    target.to_owned(),
+                    size.parse()?,
+                    hash.to_owned(),
+                    name.to_owned(),
+                );
+                if command == "FILEOFFER" {
+                    Ok(Command::FileOffer {
+                        target,
+                        id,
+                        size,
+                        hash,
+                        name,
+                    })
+                } else {
+                    Ok(Command::DirectOffer {
+                        target,
+                        id,
+                        size,
+                        hash,
+                        name,
+                    })
+                }
+            }
+            "DIRECTACCEPT" => match (middle.len(), trailing) {
+                (3, None) => Ok(Command::DirectAccept {
+                    id: middle[0].parse()?,
+                    addr: middle[1].parse()?,
+                    token: middle[2].to_owned(),
+                }),
+                _ => Err("Incorrect params for command: DIRECTACCEPT".into()),
+            },
+            "FILEACCEPT" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::FileAccept(middle[0].parse()?)),
+                _ => Err("Incorrect params for command: FILEACCEPT".into()),
+            },
+            "FILEREJECT" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::FileReject(middle[0].parse()?)),
+                _ => Err("Incorrect params for command: FILEREJECT".into()),
+            },
+            "FILECHUNK" => match (middle.len(), trailing) {
+                (2, Some(data)) => Ok(Command::FileChunk {
+                    id: middle[0].parse()?,
+                    offset: middle[1].parse()?,
+                    data: data.to_owned(),
+                }),
+                _ => Err("Incorrect params for command: FILECHUNK".into()),
+            },
+            "FILEDONE" => match (middle.len(), trailing) {
+                (2, None) if file::is_valid_hash(middle[1]) => Ok(Command::FileDone {
+                    id: middle[0].parse()?,
+                    hash: middle[1].to_owned(),
+                }),
+                _ => Err("Incorrect params for command: FILEDONE".into()),
+            },
+            "FILECANCEL" => match (middle.len(), trailing) {
+                (1, None) => Ok(Command::FileCancel(middle[0].parse()?)),
+                _ => Err("Incorrect params for command: FILECANCEL".into()),
+            },
+            "DISCOVER" => Ok(Command::Discover),
+            "ANNOUNCE" => match (middle.len(), trailing) {
+                (4, None) => Ok(Command::Announce {
+                    name: middle[0].to_owned(),
+                    port: middle[1].parse()?,
+                    tls: match middle[2] {
+                        "tls" => true,
+                        "plain" => false,
+                        other => return Err(format!("Unrecognized transport: {}", other).into()),
+                    },
+                    users: middle[3].parse()?,
+                }),
+                _ => Err("Incorrect params for command: ANNOUNCE".into()),
+            },
+            "SERVER" => match (middle.len(), trailing) {
+                (2, None) => Ok(Command::Server {
+                    name: middle[0].to_owned(),
+                    password: middle[1].to_owned(),
+                }),
+                _ => Err("Incorrect params for command: SERVER".into()),
+            },
+            "INTRODUCE" => match (middle.len(), trailing) {
+                (2, None) if nick::is_valid(middle[0]) => Ok(Command::Introduce {
+                    nick: middle[0].to_owned(),
+                    registered: middle[1].parse()?,
+                }),
+                _ => Err("Incorrect params for command: INTRODUCE".into()),
+            },
+            "ERROR" => match (middle.len(), trailing) {
+                (0, Some(msg)) => Ok(Command::ErrorReply(msg.to_owned())),
+                _ => Err("Incorrect params for command: ERROR".into()),
+            },
             other => Err(format!("Unrecognized command: {}", other).into()),
         }
     }
 }
 
+impl Command {
+    /// Returns the name of the command as it appears on the wire, without any params. Useful for
+    /// logging where the params may contain sensitive data such as passwords.
+    pub fn name(&self) -> &'static str {
+        use Command::*;
+
+        match self {
+            Nick(_) => "NICK",
+            Msg { .. } => "MSG",
+            PrivMsg { .. } => "MSG",
+            Notice { .. } => "NOTICE",
+            Action { .. } => "ACTION",
+            Quit => "QUIT",
+            Join(_) => "JOIN",
+            Part(_) => "PART",
+            Topic { .. } => "TOPIC",
+            TopicReply { .. } => "TOPICREPLY",
+            NoTopic(_) => "NOTOPIC",
+            Names(_) => "NAMES",
+            Who => "WHO",
+            NamesReply { .. } => "NAMREPLY",
+            EndOfNames => "ENDOFNAMES",
+            WhoReply { .. } => "WHOREPLY",
+            EndOfWho => "ENDOFWHO",
+            Whois(_) => "WHOIS",
+            WhoisUser { .. } => "WHOISUSER",
+            WhoisChannels { .. } => "WHOISCHANNELS",
+            WhoisAddr { .. } => "WHOISADDR",
+            EndOfWhois(_) => "ENDOFWHOIS",
+            Away(_) => "AWAY",
+            AwayReply { .. } => "AWAYREPLY",
+            Oper { .. } => "OPER",
+            YoureOper => "YOUREOPER",
+            Kick { .. } => "KICK",
+            Kill { .. } => "KILL",
+            Mute(_) => "MUTE",
+            Unmute(_) => "UNMUTE",
+            Ban { .. } => "BAN",
+            Unban(_) => "UNBAN",
+            BanList => "BANLIST",
+            BanReply { .. } => "BANREPLY",
+            EndOfBanList => "ENDOFBANLIST",
+            Motd => "MOTD",
+            MotdReply(_) => "MOTDREPLY",
+            EndOfMotd => "ENDOFMOTD",
+            NoMotd => "NOMOTD",
+            Thread(_) => "THREAD",
+            EndOfThread(_) => "ENDOFTHREAD",
+            History(_) => "HISTORY",
+            EndOfHistory => "ENDOFHISTORY",
+            Edit { .. } => "EDIT",
+            Delete(_) => "DELETE",
+            React { .. } => "REACT",
+            Unreact { .. } => "UNREACT",
+            Cap(_) => "CAP",
+            Typing { .. } => "TYPING",
+            FileOffer { .. } => "FILEOFFER",
+            FileAccept(_) => "FILEACCEPT",
+            FileReject(_) => "FILEREJECT",
+            FileChunk { .. } => "FILECHUNK",
+            FileDone { .. } => "FILEDONE",
+            FileCancel(_) => "FILECANCEL",
+            DirectOffer { .. } => "DIRECTOFFER",
+            DirectAccept { .. } => "DIRECTACCEPT",
+            Discover => "DISCOVER",
+            Announce { .. } => "ANNOUNCE",
+            Server { .. } => "SERVER",
+            Introduce { .. } => "INTRODUCE",
+            ErrorReply(_) => "ERROR",
+        }
+    }
+}
+
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Command::*;
 
         match self {
             Nick(nick) => write!(f, "NICK {}", nick),
-            Msg(msg) => write!(f, "MSG :{}", msg),
+            Msg { text, tags } => write!(f, "MSG{} :{}", tags, text),
+            PrivMsg { target, text, tags } => write!(f, "MSG {}{} :{}", target, tags, text),
+            Notice {
+                target: Some(target),
+                text,
+            } => write!(f, "NOTICE {} :{}", target, text),
+            Notice { target: None, text } => write!(f, "NOTICE :{}", text),
+            Action {
+                target: Some(target),
+                text,
+            } => write!(f, "ACTION {} :{}", target, text),
+            Action { target: None, text } => write!(f, "ACTION :{}", text),
             Quit => f.write_str("QUIT"),
+            Join(channel) => write!(f, "JOIN {}", channel),
+            Part(channel) => write!(f, "PART {}", channel),
+            Topic {
+                channel,
+                text: None,
+            } => write!(f, "TOPIC {}", channel),
+            Topic {
+                channel,
+                text: Some(text),
+            } => write!(f, "TOPIC {} :{}", channel, text),
+            TopicReply {
+                channel,
+                nick,
+                set_at,
+                text,
+            } => write!(f, "TOPICREPLY {} {} {} :{}", channel, nick, set_at, text),
+            NoTopic(channel) => write!(f, "NOTOPIC {}", channel),
+            Names(None) => f.write_str("NAMES"),
+            Names(Some(channel)) => write!(f, "NAMES {}", channel),
+            Who => f.write_str("WHO"),
+            NamesReply { nick, away } => write!(f, "NAMREPLY {} {}", nick, away_flag(*away)),
+            EndOfNames => f.write_str("ENDOFNAMES"),
+            WhoReply {
+                nick,
+                addr,
+                idle,
+                away,
+            } => write!(
+                f,
+                "WHOREPLY {} {} {} {}",
+                nick,
+                addr,
+                idle,
+                away_flag(*away)
+            ),
+            EndOfWho => f.write_str("ENDOFWHO"),
+            Whois(nick) => write!(f, "WHOIS {}", nick),
+            WhoisUser {
+                nick,
+                connected,
+                idle,
+            } => write!(f, "WHOISUSER {} {} {}", nick, connected, idle),
+            WhoisChannels { nick, channels } => {
+                write!(f, "WHOISCHANNELS {} :{}", nick, channels.join(" "))
+            }
+            WhoisAddr { nick, addr } => write!(f, "WHOISADDR {} {}", nick, addr),
+            EndOfWhois(nick) => write!(f, "ENDOFWHOIS {}", nick),
+            Away(None) => f.write_str("AWAY"),
+            Away(Some(msg)) => write!(f, "AWAY :{}", msg),
+            AwayReply { nick, msg } => write!(f, "AWAYREPLY {} :{}", nick, msg),
+            Oper { name, password } => write!(f, "OPER {} {}", name, password),
+            YoureOper => f.write_str("YOUREOPER"),
+            Kick {
+                channel,
+                nick,
+                reason: None,
+            } => write!(f, "KICK {} {}", channel, nick),
+            Kick {
+                channel,
+                nick,
+                reason: Some(reason),
+            } => write!(f, "KICK {} {} :{}", channel, nick, reason),
+            Kill { nick, reason: None } => write!(f, "KILL {}", nick),
+            Kill {
+                nick,
+                reason: Some(reason),
+            } => write!(f, "KILL {} :{}", nick, reason),
+            Mute(nick) => write!(f, "MUTE {}", nick),
+            Unmute(nick) => write!(f, "UNMUTE {}", nick),
+            Ban {
+                mask,
+                duration,
+                reason,
+            } => {
+                write!(f, "BAN {}", mask)?;
+                if let Some(duration) = duration {
+                    write!(f, " {}", duration)?;
+                }
+                if let Some(reason) = reason {
+                    write!(f, " :{}", reason)?;
+                }
+                Ok(())
+            }
+            Unban(mask) => write!(f, "UNBAN {}", mask),
+            BanList => f.write_str("BANLIST"),
+            BanReply {
+                mask,
+                set_by,
+                expires,
+                reason,
+            } => write!(f, "BANREPLY {} {} {} :{}", mask, set_by, expires, reason),
+            EndOfBanList => f.write_str("ENDOFBANLIST"),
+            Motd => f.write_str("MOTD"),
+            MotdReply(line) => write!(f, "MOTDREPLY :{}", line),
+            EndOfMotd => f.write_str("ENDOFMOTD"),
+            NoMotd => f.write_str("NOMOTD"),
+            Thread(id) => write!(f, "THREAD {}", id),
+            EndOfThread(id) => write!(f, "ENDOFTHREAD {}", id),
+            History(Some(target)) => write!(f, "HISTORY {}", target),
+            History(None) => f.write_str("HISTORY"),
+            EndOfHistory => f.write_str("ENDOFHISTORY"),
+            Edit { id, text } => write!(f, "EDIT {} :{}", id, text),
+            Delete(id) => write!(f, "DELETE {}", id),
+            React { id, emoji } => write!(f, "REACT {} {}", id, emoji),
+            Unreact { id, emoji } => write!(f, "UNREACT {} {}", id, emoji),
+            Cap(caps) => write!(f, "CAP :{}", caps.join(" ")),
+            Typing { target, typing } => {
+                let state = if *typing { "start" } else { "stop" };
+                match target {
+                    Some(target) => write!(f, "TYPING {} {}", target, state),
+                    None => write!(f, "TYPING {}", state),
+                }
+            }
+            FileOffer {
+                target,
+                id,
+                size,
+                hash,
+                name,
+            }
+            | DirectOffer {
+                target,
+                id,
+                size,
+                hash,
+                name,
+            } => {
+                write!(f, "{} {}", self.name(), target)?;
+                if let Some(id) = id {
+                    write!(f, " {}", id)?;
+                }
+                write!(f, " {} {} :{}", size, hash, name)
+            }
+            FileAccept(id) => write!(f, "FILEACCEPT {}", id),
+            FileReject(id) => write!(f, "FILEREJECT {}", id),
+            FileChunk { id, offset, data } => write!(f, "FILECHUNK {} {} :{}", id, offset, data),
+            FileDone { id, hash } => write!(f, "FILEDONE {} {}", id, hash),
+            FileCancel(id) => write!(f, "FILECANCEL {}", id),
+            DirectAccept { id, addr, token } => {
+                write!(f, "DIRECTACCEPT {} {} {}", id, addr, token)
+            }
+            Discover => f.write_str("DISCOVER"),
+            Announce {
+                name,
+                port,
+                tls,
+                users,
+            } => {
+                let transport = if *tls { "tls" } else { "plain" };
+                write!(f, "ANNOUNCE {} {} {} {}", name, port, transport, users)
+            }
+            Server { name, password } => write!(f, "SERVER {} {}", name, password),
+            Introduce { nick, registered } => write!(f, "INTRODUCE {} {}", nick, registered),
+            ErrorReply(msg) => write!(f, "ERROR :{}", msg),
+        }
+    }
+}
+
+/// Tags attached to a message as `key=value` params before the text, for example
+/// `MSG #general id=7 parent=3 reactions=👍:2,🎉:1 :text`. The server gives every message it
+/// relays an `id`, and a client replying to a message sets `parent` to the id of the message it is
+/// replying to. Messages replayed from the history carry the number of each reaction they have.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MsgTags {
+    pub id: Option<u64>,
+    pub parent: Option<u64>,
+    pub reactions: Vec<(String, u32)>,
+}
+
+impl MsgTags {
+    fn parse(params: &[&str]) -> Result<MsgTags, Box<dyn std::error::Error + Send + Sync>> {
+        let mut tags = MsgTags::default();
+        for param in params {
+            match param.split_once('=') {
+                Some(("id", id)) => tags.id = Some(id.parse()?),
+                Some(("parent", parent)) => tags.parent = Some(parent.parse()?),
+                Some(("reactions", reactions)) => {
+                    for reaction in reactions.split(',') {
+                        let (emoji, count) = reaction
+                            .rsplit_once(':')
+                            .filter(|(emoji, _)| is_valid_reaction(emoji))
+                            .ok_or_else(|| format!("Invalid reaction: {}", reaction))?;
+                        tags.reactions.push((emoji.to_owned(), count.parse()?));
+                    }
+                }
+                _ => return Err(format!("Unrecognized message tag: {}", param).into()),
+            }
+        }
+        Ok(tags)
+    }
+}
+
+/// The capability a client requests with [`Command::Cap`] to receive [`Command::Typing`].
+pub const CAP_TYPING: &str = "typing";
+
+/// The maximum length of a reaction in bytes.
+pub const MAX_REACTION_LEN: usize = 32;
+
+/// Whether `emoji` can be used as a reaction. Reactions are usually a single emoji but may be any
+/// short text without spaces, or the `,`, `:` and `=` used to write them in [`MsgTags`].
+pub fn is_valid_reaction(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= MAX_REACTION_LEN
+        && !emoji
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, ',' | ':' | '='))
+}
+
+/// Each tag is written with a leading space, so that no tags are written as an empty string.
+impl fmt::Display for MsgTags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(id) = self.id {
+            write!(f, " id={}", id)?;
+        }
+        if let Some(parent) = self.parent {
+            write!(f, " parent={}", parent)?;
         }
+        for (i, (emoji, count)) in self.reactions.iter().enumerate() {
+            let separator = if i == 0 { " reactions=" } else { "," };
+            write!(f, "{}{}:{}", separator, emoji, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Users are listed as either here (`H`) or gone (`G`) when they are away.
+fn away_flag(away: bool) -> &'static str {
+    if away {
+        "G"
+    } else {
+        "H"
+    }
+}
+
+fn parse_away_flag(flag: &str) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    match flag {
+        "H" => Ok(false),
+        "G" => Ok(true),
+        other => Err(format!("Unrecognized away flag: {}", other).into()),
     }
 }
 
@@ -104,7 +1057,10 @@ mod tests {
     #[test]
     fn parse_command_message_works() {
         let input = "MSG :this is a message";
-        let expected = Command::Msg("this is a message".to_owned());
+        let expected = Command::Msg {
+            text: "this is a message".to_owned(),
+            tags: MsgTags::default(),
+        };
 
         let result = parse_command(input);
         assert_eq!(Ok(("", expected)), result);
@@ -118,4 +1074,316 @@ mod tests {
         let result = parse_command(input);
         assert_eq!(Ok(("", expected)), result);
     }
+
+    #[test]
+    fn parse_command_who_reply_works() {
+        let input = "WHOREPLY olly 192.168.0.2:51234 30 G";
+        let expected = Command::WhoReply {
+            nick: "olly".to_owned(),
+            addr: "192.168.0.2:51234".parse().unwrap(),
+            idle: 30,
+            away: true,
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_whois_user_works() {
+        let input = "WHOISUSER olly 1660000000 42";
+        let expected = Command::WhoisUser {
+            nick: "olly".to_owned(),
+            connected: 1660000000,
+            idle: 42,
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_away_works() {
+        let input = "AWAY :out to lunch";
+        let expected = Command::Away(Some("out to lunch".to_owned()));
+        assert_eq!(Ok(("", expected)), parse_command(input));
+
+        let input = "AWAY";
+        let expected = Command::Away(None);
+        assert_eq!(Ok(("", expected)), parse_command(input));
+    }
+
+    #[test]
+    fn parse_command_private_message_works() {
+        let input = "MSG olly :psst";
+        let expected = Command::PrivMsg {
+            target: "olly".to_owned(),
+            text: "psst".to_owned(),
+            tags: MsgTags::default(),
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_message_tags_works() {
+        let input = "MSG #general id=7 parent=3 :agreed";
+        let expected = Command::PrivMsg {
+            target: "#general".to_owned(),
+            text: "agreed".to_owned(),
+            tags: MsgTags {
+                id: Some(7),
+                parent: Some(3),
+                reactions: Vec::new(),
+            },
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+
+        let input = "MSG parent=3 :agreed";
+        let expected = Command::Msg {
+            text: "agreed".to_owned(),
+            tags: MsgTags {
+                id: None,
+                parent: Some(3),
+                reactions: Vec::new(),
+            },
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+
+        let input = "MSG id=9 reactions=👍:2,🎉:1 :shipped";
+        let expected = Command::Msg {
+            text: "shipped".to_owned(),
+            tags: MsgTags {
+                id: Some(9),
+                parent: None,
+                reactions: vec![("👍".to_owned(), 2), ("🎉".to_owned(), 1)],
+            },
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+
+        assert!(parse_command("MSG #general colour=red :hi").is_err());
+    }
+
+    #[test]
+    fn parse_command_edit_works() {
+        let input = "EDIT 42 :fixed the typo";
+        let expected = Command::Edit {
+            id: 42,
+            text: "fixed the typo".to_owned(),
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_typing_works() {
+        let input = "TYPING #general start";
+        let expected = Command::Typing {
+            target: Some("#general".to_owned()),
+            typing: true,
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+        assert!(parse_command("TYPING #general maybe").is_err());
+
+        let input = "TYPING stop";
+        let expected = Command::Typing {
+            target: None,
+            typing: false,
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+        assert!(parse_command("TYPING maybe").is_err());
+    }
+
+    #[test]
+    fn parse_command_file_offer_works() {
+        let hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let input = format!("FILEOFFER bob 7 4 {} :test.txt", hash);
+        let expected = Command::FileOffer {
+            target: "bob".to_owned(),
+            id: Some(7),
+            size: 4,
+            hash: hash.to_owned(),
+            name: "test.txt".to_owned(),
+        };
+        let result = parse_command(&input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+
+        let input = format!("FILEOFFER bob 4 {} :test.txt", hash);
+        let command = parse_command(&input).unwrap().1;
+        assert!(matches!(command, Command::FileOffer { id: None, .. }));
+        assert_eq!(input, command.to_string());
+
+        let input = format!("DIRECTOFFER bob 4 {} :test.txt", hash);
+        let command = parse_command(&input).unwrap().1;
+        assert!(matches!(command, Command::DirectOffer { id: None, .. }));
+        assert_eq!(input, command.to_string());
+
+        assert!(parse_command(&format!("FILEOFFER bob 4 {} :../test.txt", hash)).is_err());
+        assert!(parse_command("FILEOFFER bob 4 abc123 :test.txt").is_err());
+    }
+
+    #[test]
+    fn parse_command_direct_accept_works() {
+        let input = "DIRECTACCEPT 7 192.168.0.2:40000 9f86d081884c7d65";
+        let expected = Command::DirectAccept {
+            id: 7,
+            addr: "192.168.0.2:40000".parse().unwrap(),
+            token: "9f86d081884c7d65".to_owned(),
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_announce_works() {
+        let input = "ANNOUNCE chat.example.lan 3000 plain 12";
+        let expected = Command::Announce {
+            name: "chat.example.lan".to_owned(),
+            port: 3000,
+            tls: false,
+            users: 12,
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_introduce_works() {
+        let input = "INTRODUCE olly 1700000000";
+        let expected = Command::Introduce {
+            nick: "olly".to_owned(),
+            registered: 1700000000,
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+
+        assert!(parse_command("INTRODUCE #general 1700000000").is_err());
+    }
+
+    #[test]
+    fn parse_command_file_chunk_works() {
+        let input = "FILECHUNK 7 2048 :dGVzdA==";
+        let expected = Command::FileChunk {
+            id: 7,
+            offset: 2048,
+            data: "dGVzdA==".to_owned(),
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_notice_works() {
+        let input = "NOTICE #general :build finished";
+        let expected = Command::Notice {
+            target: Some("#general".to_owned()),
+            text: "build finished".to_owned(),
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+
+        let input = "NOTICE :restarting in 5 minutes";
+        let expected = Command::Notice {
+            target: None,
+            text: "restarting in 5 minutes".to_owned(),
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_action_works() {
+        for input in ["ACTION :waves", "ACTION #general :waves at everyone"] {
+            let command = parse_command(input).unwrap().1;
+            assert!(matches!(command, Command::Action { .. }));
+            assert_eq!(input, command.to_string());
+        }
+    }
+
+    #[test]
+    fn parse_command_topic_works() {
+        let input = "TOPIC #general :Friday is pizza day";
+        let expected = Command::Topic {
+            channel: "#general".to_owned(),
+            text: Some("Friday is pizza day".to_owned()),
+        };
+        assert_eq!(Ok(("", expected)), parse_command(input));
+
+        let input = "TOPICREPLY #general olly 1660000000 :Friday is pizza day";
+        let expected = Command::TopicReply {
+            channel: "#general".to_owned(),
+            nick: "olly".to_owned(),
+            set_at: 1660000000,
+            text: "Friday is pizza day".to_owned(),
+        };
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_kick_works() {
+        let input = "KICK #general bob :flooding";
+        let expected = Command::Kick {
+            channel: "#general".to_owned(),
+            nick: "bob".to_owned(),
+            reason: Some("flooding".to_owned()),
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_ban_works() {
+        let input = "BAN 192.168.0.0/24 3600 :Flooding";
+        let expected = Command::Ban {
+            mask: "192.168.0.0/24".to_owned(),
+            duration: Some(3600),
+            reason: Some("Flooding".to_owned()),
+        };
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_command_motd_reply_works() {
+        let input = "MOTDREPLY :Be excellent to each other";
+        let expected = Command::MotdReply("Be excellent to each other".to_owned());
+
+        let result = parse_command(input);
+        assert_eq!(Ok(("", expected.clone())), result);
+        assert_eq!(input, expected.to_string());
+    }
 }
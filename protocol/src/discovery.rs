@@ -0,0 +1,38 @@
+//! Finding servers on the local network.
+//!
+//! A client sends [`Command::Discover`] in a UDP datagram to [`DEFAULT_PORT`], either as a
+//! broadcast or to the [`MULTICAST_ADDR`] group, and each server that receives it replies to the
+//! client with [`Command::Announce`] describing itself. Each datagram contains a single message,
+//! including the terminating CRLF.
+//!
+//! Servers also register themselves as a [`SERVICE_TYPE`] service with mDNS, so that they can be
+//! found with standard DNS-SD tools such as `avahi-browse` and `dns-sd`.
+//!
+//! [`Command::Discover`]: crate::command::Command::Discover
+//! [`Command::Announce`]: crate::command::Command::Announce
+use std::net::Ipv4Addr;
+
+/// The port servers listen on by default, for both chat connections over TCP and discovery over
+/// UDP.
+pub const DEFAULT_PORT: u16 = 3000;
+
+/// The multicast group servers join to receive discovery requests, from the range reserved for
+/// use within an organisation.
+pub const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 60, 60);
+
+/// The DNS-SD service type servers advertise themselves as over mDNS, alongside answering
+/// discovery requests.
+pub const SERVICE_TYPE: &str = "_lanchat._tcp.local.";
+
+/// The TXT record key for the name of the server, which may contain `.`s that can't be used in the
+/// name of the service instance.
+pub const TXT_NAME: &str = "name";
+
+/// The TXT record key for the version of the server.
+pub const TXT_VERSION: &str = "version";
+
+/// The TXT record key for the optional protocol features the server supports, separated by `,`.
+pub const TXT_CAPABILITIES: &str = "caps";
+
+/// The TXT record key for whether the server expects connections to use TLS, `true` or `false`.
+pub const TXT_TLS: &str = "tls";
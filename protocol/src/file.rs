@@ -0,0 +1,89 @@
+//! Files sent between users over the chat connection.
+//!
+//! The sender offers a file with [`Command::FileOffer`], giving its name, size and SHA-256 hash,
+//! and the server relays the offer to the recipient along with an id for the transfer. Once the
+//! recipient accepts with [`Command::FileAccept`] the sender sends the file in order as
+//! [`Command::FileChunk`]s of at most [`CHUNK_SIZE`] bytes, encoded with base64 so that they fit
+//! within a single line, followed by [`Command::FileDone`] carrying the hash again. The recipient
+//! checks the hash of what it received against the hash in the offer.
+//!
+//! Large files can instead be offered with [`Command::DirectOffer`] and sent over a direct
+//! connection between the clients, in which case the server only relays the offer and the
+//! [`Command::DirectAccept`] giving the address the recipient is listening on and a token that the
+//! sender must give when it connects.
+//!
+//! [`Command::FileOffer`]: crate::command::Command::FileOffer
+//! [`Command::FileAccept`]: crate::command::Command::FileAccept
+//! [`Command::FileChunk`]: crate::command::Command::FileChunk
+//! [`Command::FileDone`]: crate::command::Command::FileDone
+//! [`Command::DirectOffer`]: crate::command::Command::DirectOffer
+//! [`Command::DirectAccept`]: crate::command::Command::DirectAccept
+use base64::{engine::general_purpose::STANDARD, DecodeError, Engine};
+
+/// The maximum number of bytes of the file sent in a single chunk. Once encoded, a chunk of this
+/// size along with the rest of the line is well within the 4096 byte limit on a line.
+pub const CHUNK_SIZE: usize = 2048;
+
+/// The maximum length of a file name in bytes.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Encodes part of a file to be sent in a chunk.
+pub fn encode_chunk(data: &[u8]) -> String {
+    STANDARD.encode(data)
+}
+
+/// Decodes the part of a file sent in a chunk.
+pub fn decode_chunk(data: &str) -> Result<Vec<u8>, DecodeError> {
+    STANDARD.decode(data)
+}
+
+/// Whether `hash` is a SHA-256 hash written as lowercase hex.
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, 'a'..='f'))
+}
+
+/// Whether `name` can be used as the name of an offered file. Names never contain a path, so the
+/// recipient can't be tricked into writing outside the directory it saves files to.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name
+            .chars()
+            .any(|c| c.is_control() || matches!(c, '/' | '\\'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+    use crate::message::{LanChatMessage, Prefix};
+
+    #[test]
+    fn largest_chunk_fits_on_a_line() {
+        let msg = LanChatMessage {
+            prefix: Some(Prefix::user("x".repeat(crate::nick::MAX_LEN))),
+            command: Command::FileChunk {
+                id: u64::MAX,
+                offset: u64::MAX,
+                data: encode_chunk(&[0xff; CHUNK_SIZE]),
+            },
+        };
+        let line = msg.to_string();
+        assert!(line.len() <= 4096, "{} bytes", line.len());
+        assert_eq!(Ok(msg), line.parse());
+    }
+
+    #[test]
+    fn file_names_are_not_paths() {
+        assert!(is_valid_name("build.log"));
+        assert!(is_valid_name("notes from friday.txt"));
+        for name in ["", ".", "..", "../etc/passwd", "C:\\boot.ini", "a\nb"] {
+            assert!(!is_valid_name(name), "{:?}", name);
+        }
+    }
+}
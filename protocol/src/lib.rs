@@ -1,3 +1,6 @@
 pub mod codec;
 pub mod command;
+pub mod discovery;
+pub mod file;
 pub mod message;
+pub mod nick;
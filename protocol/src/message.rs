@@ -4,23 +4,31 @@
 //!
 //! ```text
 //! Message ::= (Prefix Space)? Command CRLF
-//! Prefix ::= ':' Nickname /* Can be expanded in the future */
+//! Prefix ::= ':' (ServerName | Nickname ('!' User)? ('@' Host)?)
 //! Command ::= Letter+ Params*
 //! Params ::= (Space Middle)* (Space ':' Trailing)?
 //! Middle ::= NoColonCRLFSpace (':' | NoColonCRLFSpace)*
 //! Trailing ::= ( ':' | Space | NoColonCRLFSpace )*
 //! NoColonCRLFSpace ::= #x00-#x09 | #x0B-#x0C | #x0E-#x1F | #x21-#x39 | #x3B-#xFF /* No Colon, CR, LF, or Space */
 //! CRLF ::= #x0D #x0A
-//! Nickname ::= ascii_alphabetical
+//! ServerName ::= Label ('.' Label)+
+//! Label ::= (ascii_alphanumeric | '-')+
+//! Nickname ::= /* See the nick module */
+//! User ::= (#x22-#x3F | #x41-#xFF)+ /* No Space, '!' or '@' */
+//! Host ::= (ascii_alphanumeric | '.' | '-' | ':')+
 //! ```
 use std::fmt;
 use std::str::FromStr;
 
 use crate::command::{parse_command, Command};
+use crate::nick::parse_nick;
 use nom::{
-    character::complete::{alpha1, char, crlf},
-    combinator::{complete, map, opt},
-    sequence::{pair, preceded, terminated},
+    branch::alt,
+    bytes::complete::take_while1,
+    character::complete::{char, crlf},
+    combinator::{complete, map, opt, recognize},
+    multi::many1,
+    sequence::{pair, preceded, terminated, tuple},
     IResult,
 };
 
@@ -83,47 +91,176 @@ fn parse_message(input: &str) -> IResult<&str, LanChatMessage> {
     ))(input)
 }
 
+/// The origin of a message, either the server itself or one of its users. Server names always
+/// contain a `.` which distinguishes them from nicks.
 #[derive(Debug, Clone, PartialEq)]
-pub struct Prefix {
-    pub nick: String,
+pub enum Prefix {
+    /// A message originating from the server with the given name.
+    Server(String),
+    /// A message originating from a user, written as `nick!user@host` where the username and host
+    /// are optional.
+    User {
+        nick: String,
+        user: Option<String>,
+        host: Option<String>,
+    },
+}
+
+impl Prefix {
+    /// A user prefix containing only a nick.
+    pub fn user(nick: impl Into<String>) -> Prefix {
+        Prefix::User {
+            nick: nick.into(),
+            user: None,
+            host: None,
+        }
+    }
+
+    /// Returns the server name or nick identifying the origin of the message.
+    pub fn name(&self) -> &str {
+        match self {
+            Prefix::Server(name) => name,
+            Prefix::User { nick, .. } => nick,
+        }
+    }
 }
 
 impl fmt::Display for Prefix {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, ":{}", self.nick)
+        match self {
+            Prefix::Server(name) => write!(f, ":{}", name),
+            Prefix::User { nick, user, host } => {
+                write!(f, ":{}", nick)?;
+                if let Some(user) = user {
+                    write!(f, "!{}", user)?;
+                }
+                if let Some(host) = host {
+                    write!(f, "@{}", host)?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
-// Prefix ::= ':' Nickname ;
+// Prefix ::= ':' (ServerName | Nickname ('!' User)? ('@' Host)?) ;
 fn parse_prefix(input: &str) -> IResult<&str, Prefix> {
-    map(preceded(char(':'), alpha1), |nick: &str| Prefix {
-        nick: nick.to_owned(),
-    })(input)
+    preceded(
+        char(':'),
+        alt((
+            map(parse_server_name, |name: &str| {
+                Prefix::Server(name.to_owned())
+            }),
+            map(
+                tuple((
+                    parse_nick,
+                    opt(preceded(char('!'), parse_user)),
+                    opt(preceded(char('@'), parse_host)),
+                )),
+                |(nick, user, host): (&str, Option<&str>, Option<&str>)| Prefix::User {
+                    nick: nick.to_owned(),
+                    user: user.map(str::to_owned),
+                    host: host.map(str::to_owned),
+                },
+            ),
+        )),
+    )(input)
+}
+
+// User ::= (#x22-#x3F | #x41-#xFF)+ ;
+fn parse_user(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c > ' ' && c != '@' && c != '!')(input)
+}
+
+// Host ::= (ascii_alphanumeric | '.' | '-' | ':')+ ;
+fn parse_host(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'))(input)
+}
+
+// ServerName ::= Label ('.' Label)+ ;
+fn parse_server_name(input: &str) -> IResult<&str, &str> {
+    let label = |input| take_while1(|c: char| c.is_ascii_alphanumeric() || c == '-')(input);
+    recognize(pair(label, many1(pair(char('.'), label))))(input)
+}
+
+/// Whether `name` is valid as the name of a server.
+pub fn is_server_name(name: &str) -> bool {
+    matches!(parse_server_name(name), Ok(("", _)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::command::MsgTags;
 
     #[test]
     fn parse_prefix_works() {
         let input = ":olly";
-        let expected = Prefix {
-            nick: "olly".to_owned(),
-        };
+        let expected = Prefix::user("olly");
 
         let output = parse_prefix(input);
         assert_eq!(Ok(("", expected)), output);
+
+        let input = ":zoë_2";
+        let expected = Prefix::user("zoë_2");
+        assert_eq!(Ok(("", expected)), parse_prefix(input));
+    }
+
+    #[test]
+    fn parse_server_prefix_works() {
+        let input = ":lanchat.local";
+        let expected = Prefix::Server("lanchat.local".to_owned());
+
+        let output = parse_prefix(input);
+        assert_eq!(Ok(("", expected.clone())), output);
+        assert_eq!(input, expected.to_string());
+    }
+
+    #[test]
+    fn parse_user_prefix_works() {
+        let cases = [
+            (":olly!olly@192.168.0.7", Some("olly"), Some("192.168.0.7")),
+            (":olly!o", Some("o"), None),
+            (":olly@fe80::1", None, Some("fe80::1")),
+        ];
+        for (input, user, host) in cases {
+            let expected = Prefix::User {
+                nick: "olly".to_owned(),
+                user: user.map(str::to_owned),
+                host: host.map(str::to_owned),
+            };
+
+            let output = parse_prefix(input);
+            assert_eq!(Ok(("", expected.clone())), output);
+            assert_eq!(input, expected.to_string());
+        }
+    }
+
+    #[test]
+    fn message_with_user_prefix_round_trips() {
+        let input = ":olly!olly@laptop.lan MSG #general :hi\r\n";
+        let message = input.parse::<LanChatMessage>().unwrap();
+
+        assert_eq!(
+            Some(Prefix::User {
+                nick: "olly".to_owned(),
+                user: Some("olly".to_owned()),
+                host: Some("laptop.lan".to_owned()),
+            }),
+            message.prefix
+        );
+        assert_eq!(input, message.to_string());
     }
 
     #[test]
     fn parse_message_works() {
         let input = ":olly MSG :Hi!, how's it going?\r\n";
         let expected = LanChatMessage {
-            prefix: Some(Prefix {
-                nick: "olly".to_owned(),
-            }),
-            command: Command::Msg("Hi!, how's it going?".to_owned()),
+            prefix: Some(Prefix::user("olly")),
+            command: Command::Msg {
+                text: "Hi!, how's it going?".to_owned(),
+                tags: MsgTags::default(),
+            },
         };
 
         let result = parse_message(input);
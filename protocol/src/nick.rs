@@ -0,0 +1,82 @@
+//! Nicknames.
+//!
+//! A nickname starts with a letter, `_`, `[` or `]`, followed by letters, digits, `_`, `-`, `[` or
+//! `]`, and is at most [`MAX_LEN`] characters long. Letters and digits may be any Unicode letters
+//! and digits, servers that only want ASCII nicks can reject the rest with [`str::is_ascii`].
+//!
+//! Nicknames are case-insensitive, `Olly` and `olly` refer to the same user, so they should be
+//! compared with [`eq`] rather than `==`.
+//!
+//! ```text
+//! Nickname ::= NickStart NickChar*
+//! NickStart ::= Letter | '_' | '[' | ']'
+//! NickChar ::= Letter | Digit | '_' | '-' | '[' | ']'
+//! ```
+use nom::{
+    bytes::complete::take_while,
+    character::complete::satisfy,
+    combinator::{recognize, verify},
+    sequence::pair,
+    IResult,
+};
+
+/// The maximum number of characters in a nickname.
+pub const MAX_LEN: usize = 32;
+
+fn is_start(c: char) -> bool {
+    c.is_alphabetic() || matches!(c, '_' | '[' | ']')
+}
+
+fn is_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '[' | ']')
+}
+
+// Nickname ::= NickStart NickChar* ;
+pub(crate) fn parse_nick(input: &str) -> IResult<&str, &str> {
+    verify(
+        recognize(pair(satisfy(is_start), take_while(is_char))),
+        |nick: &str| nick.chars().count() <= MAX_LEN,
+    )(input)
+}
+
+/// Whether `nick` is a valid nickname.
+pub fn is_valid(nick: &str) -> bool {
+    matches!(parse_nick(nick), Ok(("", _)))
+}
+
+/// Compares two nicknames ignoring case.
+pub fn eq(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_nicks() {
+        for nick in ["olly", "olly2", "dev_ops", "zoë", "[away]", "_x", "a-b"] {
+            assert!(is_valid(nick), "{}", nick);
+        }
+        for nick in [
+            "",
+            "2olly",
+            "-olly",
+            "ol.ly",
+            "ol ly",
+            "olly!",
+            &"a".repeat(MAX_LEN + 1),
+        ] {
+            assert!(!is_valid(nick), "{}", nick);
+        }
+    }
+
+    #[test]
+    fn nicks_are_case_insensitive() {
+        assert!(eq("Olly", "olly"));
+        assert!(eq("ZOË", "zoë"));
+        assert!(!eq("olly", "ollie"));
+    }
+}
@@ -0,0 +1,260 @@
+//! A local admin interface for managing a running server over a Unix domain socket.
+//!
+//! Each line sent to the socket is a command, the server replies with zero or more lines followed
+//! by a final `OK` line, or an `ERROR <reason>` line if the command failed. The commands are:
+//!
+//! ```text
+//! LIST                      List the connected clients
+//! KICK <nick> [reason]      Disconnect a client
+//! NOTICE <text>             Broadcast a notice from the server to all clients
+//! RELOAD                    Reload the config file
+//! STATS                     Show statistics about the server
+//! ```
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    internal_message::{AdminResponse, InternalMessage},
+    reload, BoxedError,
+};
+
+/// A command sent to the admin socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    List,
+    Kick {
+        nick: String,
+        reason: Option<String>,
+    },
+    Notice(String),
+    Reload,
+    Stats,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (command, rest) = match s.trim().split_once(' ') {
+            Some((command, rest)) => (command, rest.trim()),
+            None => (s.trim(), ""),
+        };
+
+        match (command.to_ascii_uppercase().as_str(), rest) {
+            ("LIST", "") => Ok(AdminCommand::List),
+            ("KICK", rest) if !rest.is_empty() => {
+                let (nick, reason) = match rest.split_once(' ') {
+                    Some((nick, reason)) => (nick, Some(reason.trim().to_owned())),
+                    None => (rest, None),
+                };
+                Ok(AdminCommand::Kick {
+                    nick: nick.to_owned(),
+                    reason,
+                })
+            }
+            ("NOTICE", text) if !text.is_empty() => Ok(AdminCommand::Notice(text.to_owned())),
+            ("RELOAD", "") => Ok(AdminCommand::Reload),
+            ("STATS", "") => Ok(AdminCommand::Stats),
+            _ => Err(format!("Unrecognized admin command: {}", s.trim())),
+        }
+    }
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminCommand::List => f.write_str("LIST"),
+            AdminCommand::Kick { nick, reason: None } => write!(f, "KICK {}", nick),
+            AdminCommand::Kick {
+                nick,
+                reason: Some(reason),
+            } => write!(f, "KICK {} {}", nick, reason),
+            AdminCommand::Notice(text) => write!(f, "NOTICE {}", text),
+            AdminCommand::Reload => f.write_str("RELOAD"),
+            AdminCommand::Stats => f.write_str("STATS"),
+        }
+    }
+}
+
+/// Binds the admin socket at `path`, replacing any stale socket left behind by a previous run.
+/// Anything else at `path`, including a socket another server is still listening on, is left
+/// alone and an error returned. The socket is only accessible to the user running the server.
+pub(crate) fn bind(path: &Path) -> Result<UnixListener, BoxedError> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(format!("{} is in use by another server", path.display()).into());
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(format!("{} exists and is not a socket", path.display()).into()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Accepts connections to the admin socket and forwards their commands to the server actor.
+/// `config_path` is the config file the server was started with, which is read again on RELOAD.
+#[instrument(name = "admin", skip_all)]
+pub(crate) async fn serve(
+    listener: UnixListener,
+    tx: mpsc::Sender<InternalMessage>,
+    config_path: Option<PathBuf>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_admin_connection(
+                    stream,
+                    tx.clone(),
+                    config_path.clone(),
+                ));
+            }
+            Err(e) => warn!(error = %e, "failed to accept admin connection"),
+        }
+    }
+}
+
+async fn handle_admin_connection(
+    stream: UnixStream,
+    tx: mpsc::Sender<InternalMessage>,
+    config_path: Option<PathBuf>,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match line.parse::<AdminCommand>() {
+            Ok(command) => {
+                info!(%command, "admin command");
+                execute(command, &tx, config_path.as_deref()).await
+            }
+            Err(e) => Err(e),
+        };
+
+        let mut out = String::new();
+        match response {
+            Ok(lines) => {
+                for line in lines {
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+                out.push_str("OK\n");
+            }
+            Err(reason) => {
+                out.push_str("ERROR ");
+                out.push_str(&reason);
+                out.push('\n');
+            }
+        }
+        if write.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn execute(
+    command: AdminCommand,
+    tx: &mpsc::Sender<InternalMessage>,
+    config_path: Option<&Path>,
+) -> AdminResponse {
+    let (respond, response) = oneshot::channel();
+    let msg = match command {
+        AdminCommand::List => InternalMessage::ListConnections { respond },
+        AdminCommand::Kick { nick, reason } => InternalMessage::Kick {
+            nick,
+            reason,
+            respond,
+        },
+        AdminCommand::Notice(text) => InternalMessage::Notice { text, respond },
+        AdminCommand::Reload => return reload::reload(config_path, tx).await,
+        AdminCommand::Stats => InternalMessage::Stats { respond },
+    };
+
+    tx.send(msg)
+        .await
+        .map_err(|_| "The server has stopped".to_owned())?;
+    response
+        .await
+        .map_err(|_| "The server has stopped".to_owned())?
+}
+
+/// Sends a command to the admin socket at `path`, returning the lines of the reply.
+pub async fn request(path: &Path, command: &AdminCommand) -> Result<Vec<String>, BoxedError> {
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+    write.write_all(format!("{}\n", command).as_bytes()).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    let mut reply = Vec::new();
+    while let Some(line) = lines.next_line().await? {
+        if line == "OK" {
+            return Ok(reply);
+        }
+        if let Some(reason) = line.strip_prefix("ERROR ") {
+            return Err(reason.to_owned().into());
+        }
+        reply.push(line);
+    }
+    Err("The admin socket closed before replying".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_command_from_str() {
+        assert_eq!(Ok(AdminCommand::List), "list".parse());
+        assert_eq!(
+            Ok(AdminCommand::Kick {
+                nick: "bob".to_owned(),
+                reason: Some("go home".to_owned()),
+            }),
+            "KICK bob go home".parse()
+        );
+        assert_eq!(
+            Ok(AdminCommand::Notice("Restarting soon".to_owned())),
+            "NOTICE Restarting soon".parse()
+        );
+        assert!("KICK".parse::<AdminCommand>().is_err());
+        assert!("RELOAD now".parse::<AdminCommand>().is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_only_replaces_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("lanchat-admin-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        // A regular file is never removed.
+        fs::write(&path, "not a socket").unwrap();
+        assert!(bind(&path).is_err());
+        assert_eq!("not a socket", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // Nor is a socket another server is listening on.
+        let listener = bind(&path).unwrap();
+        assert!(bind(&path).is_err());
+
+        // Once that server has stopped, its socket is replaced.
+        drop(listener);
+        let listener = bind(&path).unwrap();
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+//! An append-only log of moderation actions taken by server operators.
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use tracing::info;
+
+use crate::client::unix_secs;
+
+#[derive(Debug, Default)]
+pub(crate) struct AuditLog {
+    file: Option<File>,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `path` for appending, or returns a log that discards all records if
+    /// there is no path.
+    pub fn open(path: Option<&Path>) -> io::Result<AuditLog> {
+        let file = match path {
+            Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+            None => None,
+        };
+        Ok(AuditLog { file })
+    }
+
+    /// Records an action taken by the operator with the given nick.
+    pub fn record(&mut self, operator: &str, action: &str) {
+        info!(%operator, %action, "moderation action");
+        if let Some(file) = &mut self.file {
+            // A failure to write the audit log shouldn't stop the server.
+            let _ = writeln!(
+                file,
+                "{} {} {}",
+                unix_secs(SystemTime::now()),
+                operator,
+                action
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Bans that prevent clients from connecting, either by the address they connect from or by the
+//! nick they register.
+//!
+//! Bans are persisted as a TOML file so that they survive restarts, for example:
+//!
+//! ```toml
+//! [[bans]]
+//! mask = "192.168.0.0/24"
+//! set_by = "olly"
+//! reason = "Flooding"
+//! expires = 1660000000
+//! ```
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::unix_secs, BoxedError};
+
+/// What a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum BanMask {
+    /// A single IP address.
+    Ip(IpAddr),
+    /// A range of IP addresses, for example `192.168.0.0/24`.
+    Cidr(IpNet),
+    /// A nick glob, where `*` matches any sequence of characters and `?` matches a single
+    /// character.
+    Nick(String),
+}
+
+impl BanMask {
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            BanMask::Ip(banned) => *banned == ip,
+            BanMask::Cidr(net) => net.contains(&ip),
+            BanMask::Nick(_) => false,
+        }
+    }
+
+    pub fn matches_nick(&self, nick: &str) -> bool {
+        match self {
+            BanMask::Nick(glob) => glob_matches(glob, nick),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for BanMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            Ok(BanMask::Ip(ip))
+        } else if let Ok(net) = s.parse() {
+            Ok(BanMask::Cidr(net))
+        } else if s.contains(['/', '.', ':']) || s.is_empty() {
+            Err(format!("Invalid ban mask: {}", s))
+        } else {
+            Ok(BanMask::Nick(s.to_owned()))
+        }
+    }
+}
+
+impl TryFrom<String> for BanMask {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for BanMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanMask::Ip(ip) => write!(f, "{}", ip),
+            BanMask::Cidr(net) => write!(f, "{}", net),
+            BanMask::Nick(glob) => f.write_str(glob),
+        }
+    }
+}
+
+impl From<BanMask> for String {
+    fn from(mask: BanMask) -> String {
+        mask.to_string()
+    }
+}
+
+/// Matches `input` against a glob containing `*` and `?` wildcards, ignoring case the same way as
+/// `protocol::nick::eq`.
+fn glob_matches(glob: &str, input: &str) -> bool {
+    let glob: Vec<char> = glob.chars().flat_map(char::to_lowercase).collect();
+    let input: Vec<char> = input.chars().flat_map(char::to_lowercase).collect();
+
+    // Iterative matching with backtracking to the most recent `*`.
+    let (mut g, mut i) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while i < input.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, i));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == input[i] => {
+                g += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_g, star_i)) => {
+                    g = star_g + 1;
+                    i = star_i + 1;
+                    star = Some((star_g, star_i + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Ban {
+    pub mask: BanMask,
+    /// The nick of the operator that set the ban.
+    pub set_by: String,
+    pub reason: String,
+    /// When the ban expires as seconds since the unix epoch, bans without an expiry are permanent.
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    pub fn new(mask: BanMask, set_by: String, reason: String, duration: Option<Duration>) -> Ban {
+        Ban {
+            mask,
+            set_by,
+            reason,
+            expires: duration.map(|duration| unix_secs(SystemTime::now() + duration)),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires {
+            Some(expires) => UNIX_EPOCH + Duration::from_secs(expires) <= SystemTime::now(),
+            None => false,
+        }
+    }
+}
+
+/// The list of bans, saved to `path` whenever it changes if there is one.
+#[derive(Debug, Default)]
+pub(crate) struct BanList {
+    bans: Vec<Ban>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BanFile {
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Loads the ban list from `path`, a missing file is treated as an empty ban list.
+    pub fn load(path: Option<&Path>) -> Result<BanList, BoxedError> {
+        let bans = match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => toml::from_str::<BanFile>(&contents)?.bans,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            },
+            None => Vec::new(),
+        };
+
+        Ok(BanList {
+            bans,
+            path: path.map(Path::to_owned),
+        })
+    }
+
+    /// Returns the active bans.
+    pub fn iter(&self) -> impl Iterator<Item = &Ban> {
+        self.bans.iter().filter(|ban| !ban.is_expired())
+    }
+
+    pub fn find_ip(&self, ip: IpAddr) -> Option<&Ban> {
+        self.iter().find(|ban| ban.mask.matches_ip(ip))
+    }
+
+    pub fn find_nick(&self, nick: &str) -> Option<&Ban> {
+        self.iter().find(|ban| ban.mask.matches_nick(nick))
+    }
+
+    /// Adds a ban, replacing any existing ban with the same mask.
+    pub fn add(&mut self, ban: Ban) -> Result<(), BoxedError> {
+        self.bans.retain(|existing| existing.mask != ban.mask);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Removes the ban with the given mask, returning whether there was one.
+    pub fn remove(&mut self, mask: &BanMask) -> Result<bool, BoxedError> {
+        let len = self.bans.len();
+        self.bans.retain(|ban| ban.mask != *mask);
+        let removed = self.bans.len() != len;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Writes the active bans to disk, dropping any that have expired.
+    fn save(&mut self) -> Result<(), BoxedError> {
+        self.bans.retain(|ban| !ban.is_expired());
+        if let Some(path) = &self.path {
+            let file = BanFile {
+                bans: self.bans.clone(),
+            };
+            fs::write(path, toml::to_string(&file)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_mask_from_str() {
+        assert_eq!(
+            Ok(BanMask::Ip("192.168.0.5".parse().unwrap())),
+            "192.168.0.5".parse()
+        );
+        assert_eq!(
+            Ok(BanMask::Cidr("192.168.0.0/24".parse().unwrap())),
+            "192.168.0.0/24".parse()
+        );
+        assert_eq!(Ok(BanMask::Nick("bob*".to_owned())), "bob*".parse());
+        assert!("192.168.0.0/99".parse::<BanMask>().is_err());
+    }
+
+    #[test]
+    fn ban_mask_matches() {
+        let cidr: BanMask = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.matches_ip("10.1.2.3".parse().unwrap()));
+        assert!(!cidr.matches_ip("192.168.0.1".parse().unwrap()));
+
+        let nick: BanMask = "b?b*".parse().unwrap();
+        assert!(nick.matches_nick("bob"));
+        assert!(nick.matches_nick("BOBBY"));
+        assert!(!nick.matches_nick("olly"));
+        assert!(!nick.matches_ip("10.1.2.3".parse().unwrap()));
+
+        let unicode: BanMask = "zoë*".parse().unwrap();
+        assert!(unicode.matches_nick("ZOË"));
+        assert!(unicode.matches_nick("Zoë_away"));
+        assert!(!unicode.matches_nick("zoe"));
+    }
+
+    #[test]
+    fn ban_list_round_trip() {
+        let path = std::env::temp_dir().join(format!("lanchat-bans-{}.toml", std::process::id()));
+        let mut bans = BanList::load(Some(&path)).unwrap();
+        let ban = Ban::new(
+            "10.0.0.0/8".parse().unwrap(),
+            "olly".to_owned(),
+            "Flooding".to_owned(),
+            Some(Duration::from_secs(60)),
+        );
+        bans.add(ban.clone()).unwrap();
+
+        let loaded = BanList::load(Some(&path)).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(vec![&ban], loaded.iter().collect::<Vec<_>>());
+    }
+}
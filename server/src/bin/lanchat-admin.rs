@@ -0,0 +1,68 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+use server::admin::{self, AdminCommand};
+
+/// Manage a running lanchat server through its admin socket.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Path to the admin socket, as set by `admin_socket` in the server config.
+    #[arg(short, long)]
+    socket: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the connected clients.
+    List,
+    /// Disconnect a client.
+    Kick {
+        nick: String,
+        /// The reason shown to the other clients.
+        reason: Vec<String>,
+    },
+    /// Broadcast a notice to all clients.
+    Notice {
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+    /// Reload the server config file.
+    Reload,
+    /// Show statistics about the server.
+    Stats,
+}
+
+impl From<Command> for AdminCommand {
+    fn from(command: Command) -> AdminCommand {
+        match command {
+            Command::List => AdminCommand::List,
+            Command::Kick { nick, reason } => AdminCommand::Kick {
+                nick,
+                reason: (!reason.is_empty()).then(|| reason.join(" ")),
+            },
+            Command::Notice { text } => AdminCommand::Notice(text.join(" ")),
+            Command::Reload => AdminCommand::Reload,
+            Command::Stats => AdminCommand::Stats,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match admin::request(&args.socket, &args.command.into()).await {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
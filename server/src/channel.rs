@@ -0,0 +1,129 @@
+//! Channels that clients can join to talk with a subset of the connected users.
+//!
+//! Topics are kept separately from the channels, which only exist while they have members, so
+//! that a topic outlives the members who set it. Topics are persisted as a TOML file so that they
+//! survive restarts, for example:
+//!
+//! ```toml
+//! [topics."#general"]
+//! text = "Pizza on Friday"
+//! set_by = "olly"
+//! set_at = 1660000000
+//! ```
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::BoxedError;
+
+/// A channel and its members.
+#[derive(Debug, Default)]
+pub(crate) struct Channel {
+    /// The addresses of the clients that have joined the channel.
+    pub members: HashSet<SocketAddr>,
+}
+
+impl Channel {
+    /// Channels are only kept while they have members.
+    pub fn is_abandoned(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+/// The topic of a channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Topic {
+    pub text: String,
+    /// The nick of the client that set the topic.
+    pub set_by: String,
+    /// When the topic was set as seconds since the unix epoch.
+    pub set_at: u64,
+}
+
+/// The topics of channels, saved to `path` whenever they change if there is one.
+#[derive(Debug, Default)]
+pub(crate) struct TopicList {
+    topics: BTreeMap<String, Topic>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TopicFile {
+    topics: BTreeMap<String, Topic>,
+}
+
+impl TopicList {
+    /// Loads the topics from `path`, a missing file is treated as there being no topics.
+    pub fn load(path: Option<&Path>) -> Result<TopicList, BoxedError> {
+        let topics = match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => toml::from_str::<TopicFile>(&contents)?.topics,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e.into()),
+            },
+            None => BTreeMap::new(),
+        };
+
+        Ok(TopicList {
+            topics,
+            path: path.map(Path::to_owned),
+        })
+    }
+
+    pub fn get(&self, channel: &str) -> Option<&Topic> {
+        self.topics.get(channel)
+    }
+
+    /// Sets the topic of a channel, or clears it when `topic` is `None`.
+    pub fn set(&mut self, channel: &str, topic: Option<Topic>) -> Result<(), BoxedError> {
+        match topic {
+            Some(topic) => self.topics.insert(channel.to_owned(), topic),
+            None => self.topics.remove(channel),
+        };
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), BoxedError> {
+        if let Some(path) = &self.path {
+            let file = TopicFile {
+                topics: self.topics.clone(),
+            };
+            fs::write(path, toml::to_string(&file)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Channel names must start with a `#`, any other target is treated as a nick.
+pub(crate) fn is_channel_name(name: &str) -> bool {
+    name.starts_with('#') && name.len() > 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_list_round_trip() {
+        let path = std::env::temp_dir().join(format!("lanchat-topics-{}.toml", std::process::id()));
+        let mut topics = TopicList::load(Some(&path)).unwrap();
+        let topic = Topic {
+            text: "Pizza on Friday".to_owned(),
+            set_by: "olly".to_owned(),
+            set_at: 1660000000,
+        };
+        topics.set("#general", Some(topic.clone())).unwrap();
+        topics.set("#random", Some(topic.clone())).unwrap();
+        topics.set("#random", None).unwrap();
+
+        let loaded = TopicList::load(Some(&path)).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(Some(&topic), loaded.get("#general"));
+        assert_eq!(None, loaded.get("#random"));
+    }
+}
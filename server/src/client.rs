@@ -0,0 +1,118 @@
+//! Per-connection state tracked by the server actor.
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use protocol::message::Prefix;
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{internal_message::Response, limits::TokenBucket};
+
+/// The number of typing notifications a client can send in a burst.
+const TYPING_BURST: f64 = 5.0;
+/// The rate at which a client can send typing notifications after a burst.
+const TYPING_PER_SECOND: f64 = 1.0;
+
+/// A client connected to the server.
+#[derive(Debug)]
+pub(crate) struct Client {
+    /// The prefix used to identify messages from the client, `None` until the client has sent a
+    /// NICK command.
+    pub prefix: Option<Prefix>,
+    /// When the client connected to the server.
+    pub connected_at: SystemTime,
+    /// When the client last sent a message, used to calculate the idle time.
+    pub last_active: Instant,
+    /// The away message set by the client, `None` if the client is not away.
+    pub away: Option<String>,
+    /// Whether the client has authenticated as a server operator.
+    pub is_operator: bool,
+    /// Whether the client has been muted by an operator.
+    pub muted: bool,
+    /// Used to send responses to the connection task for this client only.
+    pub send: Sender<Response>,
+    /// Used to send the chunks of files sent to the client, see [`InternalMessage::Connect`].
+    ///
+    /// [`InternalMessage::Connect`]: crate::internal_message::InternalMessage::Connect
+    pub files: Sender<String>,
+    /// Used to hang up the connection, taken when the connection is hung up.
+    hang_up: Option<oneshot::Sender<Response>>,
+    /// The optional protocol features the client has requested with CAP.
+    pub capabilities: HashSet<String>,
+    /// Limits the rate of typing notifications from the client.
+    typing_rate: TokenBucket,
+}
+
+impl Client {
+    pub fn new(
+        send: Sender<Response>,
+        files: Sender<String>,
+        hang_up: oneshot::Sender<Response>,
+    ) -> Client {
+        Client {
+            prefix: None,
+            connected_at: SystemTime::now(),
+            last_active: Instant::now(),
+            away: None,
+            is_operator: false,
+            muted: false,
+            send,
+            files,
+            hang_up: Some(hang_up),
+            capabilities: HashSet::new(),
+            typing_rate: TokenBucket::full(TYPING_BURST, Instant::now()),
+        }
+    }
+
+    /// Tells the connection task to hang up, with [`Response::HangUp`] or with
+    /// [`Response::Refuse`] to send the reason first. Unlike responses sent with `send` this is
+    /// never dropped because the client is not keeping up.
+    pub fn hang_up(&mut self, response: Response) {
+        if let Some(hang_up) = self.hang_up.take() {
+            let _ = hang_up.send(response);
+        }
+    }
+
+    /// Returns the nick of the client if it has registered one.
+    pub fn nick(&self) -> Option<&str> {
+        self.prefix.as_ref().map(Prefix::name)
+    }
+
+    /// Returns the prefix of the client, or a placeholder if the client has not yet registered a
+    /// nick.
+    pub fn prefix_or_unknown(&self) -> Prefix {
+        self.prefix
+            .clone()
+            .unwrap_or_else(|| Prefix::user("unknown"))
+    }
+
+    /// Whether the client can send another typing notification, notifications over the limit are
+    /// dropped.
+    pub fn try_typing(&mut self) -> bool {
+        self.typing_rate
+            .try_take(TYPING_BURST, TYPING_PER_SECOND, Instant::now())
+    }
+
+    /// Sends a single line to the client, the line is dropped if the client is not keeping up.
+    pub fn send_line(&self, line: String) {
+        let _ = self.send.try_send(Response::Reply(vec![line]));
+    }
+
+    /// Returns the time at which the client connected as seconds since the unix epoch.
+    pub fn connected_secs(&self) -> u64 {
+        unix_secs(self.connected_at)
+    }
+
+    /// Returns the time elapsed since the client last sent a message.
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+}
+
+/// Returns `time` as seconds since the unix epoch.
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
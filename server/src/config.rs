@@ -0,0 +1,309 @@
+//! Server configuration.
+//!
+//! The configuration is read from a TOML file, for example:
+//!
+//! ```toml
+//! listen = "0.0.0.0:3000"
+//! server_name = "chat.example.lan"
+//! motd = "/etc/lanchat/motd.txt"
+//! unicode_nicks = true
+//! history_len = 1000
+//! audit_log = "/var/log/lanchat/audit.log"
+//! ban_list = "/var/lib/lanchat/bans.toml"
+//! topic_list = "/var/lib/lanchat/topics.toml"
+//! metrics_listen = "127.0.0.1:9300"
+//! admin_socket = "/run/lanchat/admin.sock"
+//! discovery = true
+//! mdns = true
+//!
+//! [limits]
+//! max_clients = 256
+//! max_per_ip = 8
+//! accepts_per_minute = 30
+//! max_file_size = 4194304
+//! max_transfers = 2
+//!
+//! [[operators]]
+//! name = "olly"
+//! password = "hunter2"
+//!
+//! [[links]]
+//! name = "floor2.example.lan"
+//! password = "correct-horse"
+//! connect = "192.168.2.10:3000"
+//! ```
+//!
+//! The config is reloaded when the server receives SIGHUP or the admin RELOAD command. Changes to
+//! `listen`, `server_name`, `metrics_listen`, `admin_socket`, `topic_list`, `discovery`, `mdns`
+//! and the addresses links connect to only take effect after a restart.
+use std::{fs, io, net::SocketAddr, path::Path, path::PathBuf};
+
+use protocol::message::is_server_name;
+use serde::Deserialize;
+
+use crate::{limits::Limits, BoxedError};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the server listens for connections on.
+    pub listen: SocketAddr,
+    /// The name the server uses as the prefix of messages it originates, it must contain a `.` to
+    /// distinguish it from a nick.
+    pub server_name: String,
+    /// File containing the message of the day sent to clients after they register.
+    pub motd: Option<PathBuf>,
+    /// Whether nicks may contain non-ASCII letters and digits.
+    pub unicode_nicks: bool,
+    /// The number of recent messages kept in memory for replies and threads.
+    pub history_len: usize,
+    /// Credentials that can be used with the OPER command to become a server operator.
+    pub operators: Vec<OperatorConfig>,
+    /// File that moderation actions are appended to, moderation actions are not recorded if this
+    /// is not set.
+    pub audit_log: Option<PathBuf>,
+    /// File that bans are persisted to, bans only last until the server is restarted if this is
+    /// not set.
+    pub ban_list: Option<PathBuf>,
+    /// File that channel topics are persisted to, topics only last until the server is restarted
+    /// if this is not set.
+    pub topic_list: Option<PathBuf>,
+    /// The address to serve Prometheus metrics on at `/metrics`, metrics are not served if this
+    /// is not set.
+    pub metrics_listen: Option<SocketAddr>,
+    /// The path of the Unix domain socket used by `lanchat-admin` to manage the server, the admin
+    /// socket is disabled if this is not set.
+    pub admin_socket: Option<PathBuf>,
+    /// Whether to answer clients looking for servers on the local network, over UDP on the same
+    /// address and port as `listen`.
+    pub discovery: bool,
+    /// Whether to advertise the server as a `_lanchat._tcp` DNS-SD service over mDNS.
+    pub mdns: bool,
+    /// Other servers that this server links with to form a single network.
+    pub links: Vec<LinkConfig>,
+    /// Limits on the connections accepted by the server.
+    pub limits: Limits,
+    /// The file the config was loaded from, if any. Used to reload the config.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            server_name: "lanchat.local".to_owned(),
+            motd: None,
+            unicode_nicks: false,
+            history_len: 1000,
+            operators: Vec::new(),
+            audit_log: None,
+            ban_list: None,
+            topic_list: None,
+            metrics_listen: None,
+            admin_socket: None,
+            discovery: true,
+            mdns: true,
+            links: Vec::new(),
+            limits: Limits::default(),
+            path: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, BoxedError> {
+        let contents = fs::read_to_string(path.as_ref())?;
+        let mut config: Config = toml::from_str(&contents)?;
+        config.path = Some(path.as_ref().to_owned());
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the configuration for values that parse but can't be used.
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_server_name(&self.server_name) {
+            return Err(format!("invalid server_name: {}", self.server_name));
+        }
+        if self.limits.max_clients == 0 {
+            return Err("limits.max_clients must be greater than 0".to_owned());
+        }
+        if self.limits.max_per_ip == 0 {
+            return Err("limits.max_per_ip must be greater than 0".to_owned());
+        }
+        if self.limits.accepts_per_minute == 0 {
+            return Err("limits.accepts_per_minute must be greater than 0".to_owned());
+        }
+        for (i, operator) in self.operators.iter().enumerate() {
+            if operator.name.is_empty() || operator.password.is_empty() {
+                return Err("operators must have a name and a password".to_owned());
+            }
+            if self.operators[..i].iter().any(|o| o.name == operator.name) {
+                return Err(format!(
+                    "operator {} is configured more than once",
+                    operator.name
+                ));
+            }
+        }
+        for (i, link) in self.links.iter().enumerate() {
+            if !is_server_name(&link.name) || link.name == self.server_name {
+                return Err(format!("invalid link name: {}", link.name));
+            }
+            if link.password.is_empty() || link.password.contains(char::is_whitespace) {
+                return Err(format!(
+                    "link {} must have a password without spaces",
+                    link.name
+                ));
+            }
+            if self.links[..i].iter().any(|l| l.name == link.name) {
+                return Err(format!("link {} is configured more than once", link.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the lines of the message of the day, there are no lines if `motd` is not set.
+    pub fn load_motd(&self) -> io::Result<Vec<String>> {
+        match &self.motd {
+            Some(path) => Ok(fs::read_to_string(path)?
+                .lines()
+                .map(str::to_owned)
+                .collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The settings that differ between `self` and `new` that can't be applied while the server
+    /// is running.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut settings = Vec::new();
+        if self.listen != new.listen {
+            settings.push("listen");
+        }
+        if self.server_name != new.server_name {
+            settings.push("server_name");
+        }
+        if self.metrics_listen != new.metrics_listen {
+            settings.push("metrics_listen");
+        }
+        if self.admin_socket != new.admin_socket {
+            settings.push("admin_socket");
+        }
+        if self.topic_list != new.topic_list {
+            settings.push("topic_list");
+        }
+        if self.discovery != new.discovery {
+            settings.push("discovery");
+        }
+        if self.mdns != new.mdns {
+            settings.push("mdns");
+        }
+        let connect = |config: &Config| -> Vec<_> {
+            config
+                .links
+                .iter()
+                .filter_map(|link| link.connect.map(|addr| (link.name.clone(), addr)))
+                .collect()
+        };
+        if connect(self) != connect(new) {
+            settings.push("links");
+        }
+        settings
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperatorConfig {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkConfig {
+    /// The server name of the other server.
+    pub name: String,
+    /// The password both servers send to authenticate the link, it must be the same in the
+    /// config of both servers.
+    pub password: String,
+    /// The address to connect to the other server on. Only one of the two servers needs to
+    /// connect, the other waits for the link to be made.
+    pub connect: Option<SocketAddr>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_from_toml() {
+        let input = r#"
+            listen = "127.0.0.1:4000"
+
+            [[operators]]
+            name = "olly"
+            password = "hunter2"
+
+            [[links]]
+            name = "floor2.example.lan"
+            password = "correct-horse"
+            connect = "192.168.2.10:3000"
+        "#;
+        let expected = Config {
+            listen: "127.0.0.1:4000".parse().unwrap(),
+            server_name: "lanchat.local".to_owned(),
+            motd: None,
+            unicode_nicks: false,
+            history_len: 1000,
+            operators: vec![OperatorConfig {
+                name: "olly".to_owned(),
+                password: "hunter2".to_owned(),
+            }],
+            audit_log: None,
+            ban_list: None,
+            topic_list: None,
+            metrics_listen: None,
+            admin_socket: None,
+            discovery: true,
+            mdns: true,
+            links: vec![LinkConfig {
+                name: "floor2.example.lan".to_owned(),
+                password: "correct-horse".to_owned(),
+                connect: Some("192.168.2.10:3000".parse().unwrap()),
+            }],
+            limits: Limits::default(),
+            path: None,
+        };
+
+        assert_eq!(expected, toml::from_str(input).unwrap());
+    }
+
+    #[test]
+    fn validate_and_restart_required() {
+        let mut config = Config::default();
+        assert_eq!(Ok(()), config.validate());
+
+        let operator = OperatorConfig {
+            name: "olly".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        config.operators = vec![operator.clone(), operator];
+        assert!(config.validate().is_err());
+
+        let new = Config {
+            listen: "127.0.0.1:4000".parse().unwrap(),
+            server_name: "floor1.example.lan".to_owned(),
+            limits: Limits {
+                max_clients: 1,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        assert_eq!(
+            vec!["listen", "server_name"],
+            Config::default().restart_required(&new)
+        );
+    }
+}
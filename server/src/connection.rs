@@ -1,45 +1,424 @@
-use std::net::SocketAddr;
+use std::{future::Future, io::ErrorKind, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
 
-use futures::{SinkExt, StreamExt};
-use protocol::codec::LanChatCodec;
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use protocol::{
+    codec::{LanChatCodec, LanChatCodecError},
+    command::Command,
+    message::LanChatMessage,
+};
 use tokio::{
     net::TcpStream,
-    sync::{broadcast::Receiver, mpsc::Sender, oneshot},
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        mpsc, oneshot,
+    },
+    time::timeout,
 };
 use tokio_util::codec::Framed;
+use tracing::{debug, info, instrument, warn, Span};
+
+use crate::{
+    internal_message::{InternalMessage, Response},
+    metrics::{Metered, Metrics},
+};
+
+/// How long to wait for a recipient to make room for the next line of a file transfer before the
+/// transfer is cancelled.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
 
-use crate::internal_message::{InternalMessage, Response};
+/// A line of a file transfer being passed on to the recipient, resolving to the id of the transfer
+/// and whether it stalled.
+type Relaying = Pin<Box<dyn Future<Output = (u64, bool)> + Send>>;
 
+type ClientFrame = Framed<Metered<TcpStream>, LanChatCodec>;
+type FrameSink = SplitSink<ClientFrame, String>;
+type FrameStream = SplitStream<ClientFrame>;
+
+/// The channels a connection task receives lines and responses on, other than the socket.
+pub(crate) struct Receivers {
+    /// Broadcasts meant for every client, links to other servers have none.
+    pub broadcast: Option<Receiver<String>>,
+    /// Responses sent directly to this client on behalf of another.
+    pub direct: mpsc::Receiver<Response>,
+    /// The chunks of files sent to this client.
+    pub files: mpsc::Receiver<String>,
+    /// Tells the connection task to hang up, `None` if the server actor never hangs up the
+    /// connection itself.
+    pub hang_up: Option<oneshot::Receiver<Response>>,
+    /// Lines for the other server when the connection is a link, see [`Response::Linked`].
+    pub link: Option<mpsc::Receiver<String>>,
+}
+
+#[instrument(name = "connection", skip_all, fields(%addr, nick = tracing::field::Empty))]
 pub(crate) async fn handle_connection(
     socket: TcpStream,
     addr: SocketAddr,
-    tx: Sender<InternalMessage>,
-    mut msg_broadcast: Receiver<String>,
+    tx: mpsc::Sender<InternalMessage>,
+    mut receivers: Receivers,
+    metrics: Arc<Metrics>,
 ) {
+    let socket = Metered::new(socket, metrics.clone());
     let (mut send_frame, mut recv_frame) =
         Framed::new(socket, LanChatCodec::with_max_length(4096)).split();
+    metrics.client_connected();
 
+    // Whether the last frame received was an error, and whether reading should resume after it.
+    let mut after_error = false;
+    let mut resume = false;
+    // A line of a file transfer waiting for space in the recipient's queue, and a further line
+    // of a file transfer from this client that is held until the pending line has been passed on.
+    let mut relay: Option<Relaying> = None;
+    let mut held: Option<LanChatMessage> = None;
     loop {
-        tokio::select!(
-            msg = recv_frame.next() => {
-                // TODO: Handle errors
-                // TODO: Handle case where client hangs up without sending QUIT msg
-                if let Some(Ok(msg)) = msg {
-                    let (once_send, once_recv) = oneshot::channel();
-                    let _ = tx.send(InternalMessage::new(addr, msg, once_send)).await;
-                    if let Ok(response) = once_recv.await {
-                        match response {
-                            Response::Ack => {},
-                            Response::HangUp => { break; }
+        if resume {
+            resume = false;
+            (send_frame, recv_frame) = resume_reading(send_frame, recv_frame);
+        }
+
+        // The response from the server actor to a message from this client, a response sent
+        // directly to this client on behalf of another, or the server actor hanging up. Branches
+        // are polled in order, so that lines for this client are written before more is read
+        // from it, and chunks of files sent to this client are only written when there is nothing
+        // else to do.
+        let response = tokio::select!(
+            biased;
+            response = hung_up(&mut receivers.hang_up) => Some(response),
+            msg = recv_broadcast(&mut receivers.broadcast) => {
+                match msg {
+                    Ok(msg) => {
+                        let _ = send_frame.send(msg).await;
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "connection lagged behind broadcast");
+                        metrics.broadcast_lagged();
+                    }
+                    Err(RecvError::Closed) => {}
+                }
+                None
+            }
+            Some(response) = receivers.direct.recv() => Some(response),
+            line = recv_link(&mut receivers.link) => {
+                match line {
+                    Some(line) => {
+                        let _ = send_frame.send(line).await;
+                    }
+                    // The server actor has dropped the link.
+                    None => break,
+                }
+                None
+            }
+            (transfer, stalled) = relayed(&mut relay) => {
+                if stalled {
+                    warn!(transfer, "file transfer stalled");
+                    let cancel = LanChatMessage {
+                        prefix: None,
+                        command: Command::FileCancel(transfer),
+                    };
+                    let (once_send, _) = oneshot::channel();
+                    let _ = tx.send(InternalMessage::new(addr, cancel, once_send)).await;
+                    let reason = format!("File transfer {} stalled and was cancelled", transfer);
+                    let _ = send_frame.send(error_line(reason)).await;
+                }
+                match held.take() {
+                    Some(msg) => request(&tx, addr, msg, &metrics).await,
+                    None => None,
+                }
+            }
+            msg = recv_frame.next(), if held.is_none() => {
+                match msg {
+                    // Only one line of a file transfer is relayed at a time, reading stops until
+                    // the recipient has made room for the pending line.
+                    Some(Ok(msg)) if relay.is_some() && is_file_data(&msg.command) => {
+                        held = Some(msg);
+                        None
+                    }
+                    Some(Ok(msg)) => request(&tx, addr, msg, &metrics).await,
+                    Some(Err(e)) => {
+                        warn!(error = %e, "codec error");
+                        metrics.codec_error(&e);
+                        // Input that isn't UTF-8 is reported as invalid data, any other IO error
+                        // means the connection is broken.
+                        if matches!(&e, LanChatCodecError::Io(e) if e.kind() != ErrorKind::InvalidData) {
+                            break;
                         }
+                        after_error = true;
+                        Some(Response::Reply(vec![error_line(e.to_string())]))
+                    }
+                    // After an error the stream ends once, rather than the client having hung up.
+                    None if after_error => {
+                        after_error = false;
+                        resume = true;
+                        None
+                    }
+                    // The client has hung up without sending a QUIT command.
+                    None => {
+                        info!("client hung up");
+                        break;
                     }
                 }
             }
-            msg = msg_broadcast.recv() => {
-                if let Ok(msg) = msg {
-                    let _ = send_frame.send(msg).await;
+            Some(line) = receivers.files.recv() => {
+                let _ = send_frame.send(line).await;
+                None
+            }
+        );
+
+        match response {
+            Some(Response::Ack) | None => {}
+            Some(Response::Linked(lines)) => {
+                info!("linked to another server");
+                receivers.broadcast = None;
+                receivers.link = Some(lines);
+            }
+            Some(Response::Reply(replies)) => {
+                for reply in replies {
+                    let _ = send_frame.feed(reply).await;
                 }
+                let _ = send_frame.flush().await;
+            }
+            Some(Response::Relay { to, line, transfer }) => {
+                // An error means the recipient has disconnected, the server actor cancels the
+                // transfer when it learns of the disconnection.
+                relay = Some(Box::pin(async move {
+                    let stalled = timeout(RELAY_TIMEOUT, to.send(line)).await.is_err();
+                    (transfer, stalled)
+                }));
+            }
+            Some(Response::Refuse(reason)) => {
+                info!(%reason, "refused connection");
+                let _ = send_frame.send(error_line(reason)).await;
+                break;
+            }
+            Some(Response::HangUp) => {
+                break;
+            }
+        }
+    }
+
+    info!("disconnected");
+    metrics.client_disconnected();
+    let _ = tx.send(InternalMessage::Disconnect { addr }).await;
+}
+
+/// Carries on reading from a connection after the stream of frames has ended because of an error.
+///
+/// The stream stops decoding after an error until more is read from the socket, so it is rebuilt
+/// to decode any lines the client sent after the invalid one straight away.
+fn resume_reading(send_frame: FrameSink, recv_frame: FrameStream) -> (FrameSink, FrameStream) {
+    let framed = send_frame
+        .reunite(recv_frame)
+        .expect("halves of the same connection");
+    Framed::from_parts(framed.into_parts()).split()
+}
+
+/// Sends a message from the client to the server actor and waits for the response.
+async fn request(
+    tx: &mpsc::Sender<InternalMessage>,
+    addr: SocketAddr,
+    msg: LanChatMessage,
+    metrics: &Metrics,
+) -> Option<Response> {
+    if let Command::Nick(nick) = &msg.command {
+        Span::current().record("nick", nick.as_str());
+    }
+    debug!(command = msg.command.name(), "received command");
+    metrics.message_received();
+    let (once_send, once_recv) = oneshot::channel();
+    let _ = tx.send(InternalMessage::new(addr, msg, once_send)).await;
+    once_recv.await.ok()
+}
+
+/// Whether a command is a line of a file transfer, which the server actor answers with
+/// [`Response::Relay`].
+fn is_file_data(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::FileChunk { .. } | Command::FileDone { .. }
+    )
+}
+
+/// Waits for a pending line of a file transfer to be passed on to the recipient, returning the id
+/// of the transfer and whether it stalled.
+async fn relayed(relay: &mut Option<Relaying>) -> (u64, bool) {
+    match relay {
+        Some(pending) => {
+            let relayed = pending.await;
+            *relay = None;
+            relayed
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the server actor to hang up the connection.
+async fn hung_up(hang_up: &mut Option<oneshot::Receiver<Response>>) -> Response {
+    if let Some(recv) = hang_up {
+        match recv.await {
+            Ok(response) => return response,
+            // The client has been removed without hanging up, for example after a QUIT command.
+            Err(_) => *hang_up = None,
+        }
+    }
+    std::future::pending().await
+}
+
+/// Receives the next line for the other server, connections to clients never receive one.
+async fn recv_link(link: &mut Option<mpsc::Receiver<String>>) -> Option<String> {
+    match link {
+        Some(link) => link.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Receives the next broadcast, links to other servers have no receiver and never receive one.
+async fn recv_broadcast(msg_broadcast: &mut Option<Receiver<String>>) -> Result<String, RecvError> {
+    match msg_broadcast {
+        Some(msg_broadcast) => msg_broadcast.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends the reason a connection was refused to the client before hanging up.
+pub(crate) async fn refuse_connection(socket: TcpStream, reason: String) {
+    let mut frame = Framed::new(socket, LanChatCodec::with_max_length(4096));
+    let _ = frame.send(error_line(reason)).await;
+}
+
+fn error_line(reason: String) -> String {
+    LanChatMessage {
+        prefix: None,
+        command: Command::ErrorReply(reason),
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::broadcast,
+    };
+
+    /// A connection handled by `handle_connection`, with the client's end of the socket and the
+    /// channels the server actor would use.
+    struct TestConnection {
+        client: BufReader<TcpStream>,
+        actor: mpsc::Receiver<InternalMessage>,
+        direct: mpsc::Sender<Response>,
+    }
+
+    async fn start() -> TestConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+
+        let (tx, actor) = mpsc::channel(8);
+        let (b_send, b_recv) = broadcast::channel(8);
+        let (direct, direct_recv) = mpsc::channel(8);
+        let (_, files_recv) = mpsc::channel(8);
+        let receivers = Receivers {
+            broadcast: Some(b_recv),
+            direct: direct_recv,
+            files: files_recv,
+            hang_up: None,
+            link: None,
+        };
+        tokio::spawn(async move {
+            let _b_send = b_send;
+            handle_connection(socket, addr, tx, receivers, Arc::new(Metrics::default())).await
+        });
+        TestConnection {
+            client: BufReader::new(client),
+            actor,
+            direct,
+        }
+    }
+
+    async fn read_line(client: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        timeout(Duration::from_secs(5), client.read_line(&mut line))
+            .await
+            .unwrap()
+            .unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn invalid_lines_are_answered_without_hanging_up() {
+        let mut conn = start().await;
+        let too_long = format!("MSG :{}\r\n", "a".repeat(5000));
+        conn.client
+            .get_mut()
+            .write_all(format!("NICK 2olly\r\n{}NICK olly\r\n", too_long).as_bytes())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            "ERROR :Failed to parse message\r\n",
+            read_line(&mut conn.client).await
+        );
+        assert_eq!(
+            "ERROR :Maximum message length exceeded\r\n",
+            read_line(&mut conn.client).await
+        );
+        match conn.actor.recv().await {
+            Some(InternalMessage::Message { msg, respond, .. }) => {
+                assert_eq!(Command::Nick("olly".to_owned()), msg.command);
+                let _ = respond.send(Response::Reply(vec!["NOMOTD\r\n".to_owned()]));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert_eq!("NOMOTD\r\n", read_line(&mut conn.client).await);
+    }
+
+    #[tokio::test]
+    async fn stalled_file_transfer_does_not_hold_up_other_lines() {
+        let mut conn = start().await;
+        let (files, mut files_recv) = mpsc::channel(1);
+        files.send("earlier chunk".to_owned()).await.unwrap();
+        conn.client
+            .get_mut()
+            .write_all(b"FILECHUNK 1 0 :dGVzdA==\r\nFILECHUNK 1 4 :dGVzdA==\r\n")
+            .await
+            .unwrap();
+
+        match conn.actor.recv().await {
+            Some(InternalMessage::Message { msg, respond, .. }) => {
+                assert!(matches!(msg.command, Command::FileChunk { offset: 0, .. }));
+                let _ = respond.send(Response::Relay {
+                    to: files,
+                    line: "first chunk".to_owned(),
+                    transfer: 1,
+                });
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // Lines for the client are still written while the recipient is full, but the next chunk
+        // isn't read until there is room for the first.
+        conn.direct
+            .send(Response::Reply(vec!["PONG\r\n".to_owned()]))
+            .await
+            .unwrap();
+        assert_eq!("PONG\r\n", read_line(&mut conn.client).await);
+        assert!(timeout(Duration::from_millis(100), conn.actor.recv())
+            .await
+            .is_err());
+
+        assert_eq!(Some("earlier chunk".to_owned()), files_recv.recv().await);
+        match conn.actor.recv().await {
+            Some(InternalMessage::Message { msg, .. }) => {
+                assert!(matches!(msg.command, Command::FileChunk { offset: 4, .. }));
             }
-        )
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert_eq!(Some("first chunk".to_owned()), files_recv.recv().await);
     }
 }
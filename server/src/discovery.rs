@@ -0,0 +1,133 @@
+//! Answers clients looking for servers on the local network, see [`protocol::discovery`].
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use protocol::{command::Command, discovery::MULTICAST_ADDR, message::LanChatMessage};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, instrument, warn};
+
+use crate::internal_message::InternalMessage;
+
+/// Binds the UDP socket that discovery requests are received on. When listening on all IPv4
+/// interfaces the socket also joins the discovery multicast group, failing to join is logged but
+/// broadcast requests are still answered.
+pub(crate) async fn bind(listen: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(listen).await?;
+    if listen.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+        if let Err(e) = socket.join_multicast_v4(MULTICAST_ADDR, Ipv4Addr::UNSPECIFIED) {
+            warn!(error = %e, "failed to join discovery multicast group");
+        }
+    }
+    Ok(socket)
+}
+
+/// Replies to each discovery request with a description of the server. Requests from outside
+/// the local network are ignored, so the server can't be used to send replies to a spoofed
+/// address on the internet.
+#[instrument(name = "discovery", skip_all)]
+pub(crate) async fn serve(socket: UdpSocket, tx: mpsc::Sender<InternalMessage>) {
+    let mut buf = [0; 512];
+    loop {
+        let (n, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!(error = %e, "failed to receive discovery request");
+                continue;
+            }
+        };
+        let is_request = std::str::from_utf8(&buf[..n])
+            .ok()
+            .and_then(|request| request.parse::<LanChatMessage>().ok())
+            .is_some_and(|request| request.command == Command::Discover);
+        if !is_request || !is_local(addr.ip()) {
+            continue;
+        }
+        debug!(%addr, "discovery request");
+
+        let (respond, announce) = oneshot::channel();
+        if tx
+            .send(InternalMessage::Discover { respond })
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let announce = match announce.await {
+            Ok(announce) => announce,
+            // The server actor has stopped.
+            Err(_) => return,
+        };
+        let reply = LanChatMessage {
+            prefix: None,
+            command: announce,
+        };
+        let _ = socket.send_to(reply.to_string().as_bytes(), addr).await;
+    }
+}
+
+/// Whether `ip` belongs to the local network: a loopback, private or link-local address.
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            ip.is_loopback() || unique_local || link_local
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn discovery_requests_are_answered() {
+        let socket = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(serve(socket, tx));
+        tokio::spawn(async move {
+            while let Some(InternalMessage::Discover { respond }) = rx.recv().await {
+                let _ = respond.send(Command::Announce {
+                    name: "lanchat.local".to_owned(),
+                    port: 3000,
+                    tls: false,
+                    users: 2,
+                });
+            }
+        });
+
+        let servers = client::discovery::discover_at(&[addr], Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert_eq!(1, servers.len());
+        assert_eq!("lanchat.local", servers[0].name);
+        assert_eq!(SocketAddr::from(([127, 0, 0, 1], 3000)), servers[0].addr);
+        assert!(!servers[0].tls);
+        assert_eq!(2, servers[0].users);
+    }
+
+    #[test]
+    fn only_local_addresses_are_answered() {
+        for ip in [
+            "127.0.0.1",
+            "192.168.0.2",
+            "10.1.2.3",
+            "169.254.0.1",
+            "fe80::1",
+            "fd00::1",
+        ] {
+            assert!(is_local(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "2001:db8::1"] {
+            assert!(!is_local(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Recent messages kept by the server so that clients can refer back to them, for example to
+//! reply to a message or fetch a thread.
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    net::SocketAddr,
+};
+
+use protocol::{
+    command::{Command, MsgTags},
+    message::{LanChatMessage, Prefix},
+};
+
+/// A bounded store of the most recent messages, the oldest message is dropped once the store is
+/// full.
+#[derive(Debug)]
+pub(crate) struct History {
+    /// Messages ordered by id, which is also the order they were sent in.
+    messages: VecDeque<StoredMessage>,
+    next_id: u64,
+    capacity: usize,
+}
+
+/// A message kept in the history.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredMessage {
+    pub id: u64,
+    pub from: Prefix,
+    /// The connection the message was sent from, used to check that edits come from the author.
+    pub sent_by: SocketAddr,
+    /// The channel or nick the message was sent to, `None` if it was sent to everyone.
+    pub target: Option<String>,
+    pub text: String,
+    /// The id of the message this message is a reply to.
+    pub parent: Option<u64>,
+    /// Deleted messages are kept without their text so that threads containing them still hang
+    /// together, but are never shown.
+    pub deleted: bool,
+    /// The clients that have reacted to the message with each reaction.
+    pub reactions: BTreeMap<String, HashSet<SocketAddr>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            messages: VecDeque::new(),
+            next_id: 1,
+            capacity,
+        }
+    }
+
+    /// Changes the number of messages kept, dropping the oldest messages if there are now too
+    /// many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.messages.len() > capacity {
+            self.messages.pop_front();
+        }
+    }
+
+    /// Records a message, returning the id assigned to it.
+    pub fn push(
+        &mut self,
+        from: Prefix,
+        sent_by: SocketAddr,
+        target: Option<String>,
+        text: String,
+        parent: Option<u64>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.messages.push_back(StoredMessage {
+            id,
+            from,
+            sent_by,
+            target,
+            text,
+            parent,
+            deleted: false,
+            reactions: BTreeMap::new(),
+        });
+        self.set_capacity(self.capacity);
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&StoredMessage> {
+        self.messages
+            .binary_search_by_key(&id, |message| message.id)
+            .ok()
+            .map(|i| &self.messages[i])
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut StoredMessage> {
+        self.messages
+            .binary_search_by_key(&id, |message| message.id)
+            .ok()
+            .map(|i| &mut self.messages[i])
+    }
+
+    /// Returns the messages that haven't been deleted, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &StoredMessage> {
+        self.messages.iter().filter(|message| !message.deleted)
+    }
+
+    /// Returns the messages in the thread containing the message with the given id, oldest
+    /// first, leaving out deleted messages. The thread starts at the oldest ancestor still in the
+    /// history.
+    pub fn thread(&self, id: u64) -> Vec<&StoredMessage> {
+        let mut root = match self.get(id) {
+            Some(message) => message,
+            None => return Vec::new(),
+        };
+        while let Some(parent) = root.parent.and_then(|parent| self.get(parent)) {
+            root = parent;
+        }
+
+        // Replies are always newer than the message they reply to, so a single pass from the
+        // root finds every message in the thread.
+        let mut ids = HashSet::from([root.id]);
+        self.messages
+            .iter()
+            .skip_while(|message| message.id != root.id)
+            .filter(|message| {
+                let in_thread = message.id == root.id
+                    || message.parent.is_some_and(|parent| ids.contains(&parent));
+                if in_thread {
+                    ids.insert(message.id);
+                }
+                in_thread
+            })
+            .filter(|message| !message.deleted)
+            .collect()
+    }
+}
+
+impl StoredMessage {
+    /// Adds a reaction from the client at `addr`, returning whether the reaction is new.
+    pub fn react(&mut self, emoji: &str, addr: SocketAddr) -> bool {
+        self.reactions
+            .entry(emoji.to_owned())
+            .or_default()
+            .insert(addr)
+    }
+
+    /// Removes a reaction from the client at `addr`, returning whether there was a reaction to
+    /// remove.
+    pub fn unreact(&mut self, emoji: &str, addr: SocketAddr) -> bool {
+        let removed = self
+            .reactions
+            .get_mut(emoji)
+            .is_some_and(|reacted| reacted.remove(&addr));
+        self.reactions.retain(|_, reacted| !reacted.is_empty());
+        removed
+    }
+
+    /// The message as it was relayed when it was sent, along with its reactions.
+    pub fn to_message(&self) -> LanChatMessage {
+        let tags = MsgTags {
+            id: Some(self.id),
+            parent: self.parent,
+            reactions: self
+                .reactions
+                .iter()
+                .map(|(emoji, reacted)| (emoji.clone(), reacted.len() as u32))
+                .collect(),
+        };
+        let command = match &self.target {
+            Some(target) => Command::PrivMsg {
+                target: target.clone(),
+                text: self.text.clone(),
+                tags,
+            },
+            None => Command::Msg {
+                text: self.text.clone(),
+                tags,
+            },
+        };
+        LanChatMessage {
+            prefix: Some(self.from.clone()),
+            command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(history: &mut History, text: &str, parent: Option<u64>) -> u64 {
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        history.push(Prefix::user("olly"), addr, None, text.to_owned(), parent)
+    }
+
+    #[test]
+    fn thread_contains_replies_to_replies() {
+        let mut history = History::new(8);
+        let root = push(&mut history, "lunch?", None);
+        let other = push(&mut history, "unrelated", None);
+        let reply = push(&mut history, "yes", Some(root));
+        let nested = push(&mut history, "where?", Some(reply));
+        push(&mut history, "also unrelated", Some(other));
+
+        let thread: Vec<_> = history.thread(nested).iter().map(|m| m.id).collect();
+        assert_eq!(vec![root, reply, nested], thread);
+
+        // Deleting a message leaves the rest of the thread intact.
+        history.get_mut(reply).unwrap().deleted = true;
+        let thread: Vec<_> = history.thread(nested).iter().map(|m| m.id).collect();
+        assert_eq!(vec![root, nested], thread);
+    }
+
+    #[test]
+    fn reactions_are_counted_per_client() {
+        let mut history = History::new(8);
+        let id = push(&mut history, "shipped", None);
+        let olly = "127.0.0.1:5000".parse().unwrap();
+        let bob = "127.0.0.1:5001".parse().unwrap();
+
+        let message = history.get_mut(id).unwrap();
+        assert!(message.react("👍", olly));
+        assert!(!message.react("👍", olly));
+        assert!(message.react("👍", bob));
+        assert!(message.react("🎉", bob));
+        assert!(message.unreact("🎉", bob));
+        assert!(!message.unreact("🎉", bob));
+
+        assert_eq!(
+            ":olly MSG id=1 reactions=👍:2 :shipped\r\n",
+            message.to_message().to_string()
+        );
+    }
+
+    #[test]
+    fn oldest_messages_are_dropped() {
+        let mut history = History::new(2);
+        let first = push(&mut history, "one", None);
+        push(&mut history, "two", None);
+        let third = push(&mut history, "three", None);
+
+        assert!(history.get(first).is_none());
+        assert_eq!("three", history.get(third).unwrap().text);
+    }
+}
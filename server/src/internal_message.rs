@@ -4,28 +4,106 @@
 //! the main actor orchestrating the server.
 use std::net::SocketAddr;
 
-use protocol::message::LanChatMessage;
-use tokio::sync::oneshot::Sender;
+use protocol::{command::Command, message::LanChatMessage};
+use tokio::sync::{mpsc, oneshot::Sender};
+
+use crate::{audit::AuditLog, ban::BanList, config::Config};
 
 /// A type for sending messages from a connection to the main actor.
 #[derive(Debug)]
-pub struct InternalMessage {
-    /// The address of the connected client.
-    pub addr: SocketAddr,
-    /// The message sent from the client to the server.
-    pub msg: LanChatMessage,
-    /// The sending half of a oneshot channel used to send a `Response` from the server actor back
-    /// to the client once `msg` has been processed.
-    pub respond: Sender<Response>,
+pub enum InternalMessage {
+    /// A client has connected to the server.
+    Connect {
+        /// The address of the connected client.
+        addr: SocketAddr,
+        /// Used to send responses directly to the connected client, outside of replies to its own
+        /// messages. For example a message from another client or an operator hanging up the
+        /// connection.
+        send: mpsc::Sender<Response>,
+        /// Used to send the chunks of files sent to the client, which are only written to the
+        /// connection when there is nothing else to send so that file transfers don't hold up
+        /// chat.
+        files: mpsc::Sender<String>,
+        /// Used to hang up the connection, for example when an operator kills or bans the client,
+        /// which has to happen even when `send` is full.
+        hang_up: Sender<Response>,
+        /// Used to tell the task accepting connections whether the connection has been accepted
+        /// or refused.
+        respond: Sender<Response>,
+    },
+    /// A connection to another server has been made for a link configured with `connect`, see
+    /// the [`link`] module.
+    ///
+    /// [`link`]: crate::link
+    Link {
+        /// The address of the other server.
+        addr: SocketAddr,
+        /// The server name of the other server.
+        name: String,
+        /// Used to send lines to the other server.
+        send: mpsc::Sender<String>,
+        /// Used to tell the task that made the connection whether the link can go ahead, it is
+        /// refused if the other server is already linked.
+        respond: Sender<Response>,
+    },
+    /// A message has been received from a connected client.
+    Message {
+        /// The address of the connected client.
+        addr: SocketAddr,
+        /// The message sent from the client to the server.
+        msg: LanChatMessage,
+        /// The sending half of a oneshot channel used to send a `Response` from the server actor
+        /// back to the client once `msg` has been processed.
+        respond: Sender<Response>,
+    },
+    /// A client has disconnected from the server, either by hanging up or after sending a QUIT
+    /// command.
+    Disconnect {
+        /// The address of the disconnected client.
+        addr: SocketAddr,
+    },
+    /// List the connected clients, sent from the admin socket.
+    ListConnections { respond: Sender<AdminResponse> },
+    /// Disconnect the client with the given nick, sent from the admin socket.
+    Kick {
+        nick: String,
+        reason: Option<String>,
+        respond: Sender<AdminResponse>,
+    },
+    /// Broadcast a notice from the server to all clients, sent from the admin socket.
+    Notice {
+        text: String,
+        respond: Sender<AdminResponse>,
+    },
+    /// Replace the configuration used by the server actor, sent from the admin socket or after
+    /// the server receives SIGHUP.
+    Reload {
+        config: Box<Config>,
+        /// The audit log named by the new config.
+        audit: AuditLog,
+        /// The bans read from the ban list named by the new config, `None` if the new config
+        /// doesn't name a ban list and the current bans should be kept.
+        bans: Option<BanList>,
+        /// The lines of the message of the day named by the new config.
+        motd: Vec<String>,
+        respond: Sender<AdminResponse>,
+    },
+    /// Report statistics about the server, sent from the admin socket.
+    Stats { respond: Sender<AdminResponse> },
+    /// Describe the server with [`Command::Announce`] in reply to a discovery request.
+    Discover { respond: Sender<Command> },
 }
 
+/// The lines sent back to the admin socket, or the reason the admin command failed.
+pub type AdminResponse = Result<Vec<String>, String>;
+
 impl InternalMessage {
     pub fn new(
         addr: SocketAddr,
         msg: LanChatMessage,
         respond: Sender<Response>,
     ) -> InternalMessage {
-        InternalMessage { addr, msg, respond }
+        InternalMessage::Message { addr, msg, respond }
     }
 }
 
@@ -34,7 +112,26 @@ impl InternalMessage {
 pub enum Response {
     /// A straightforward acknowledgment that the command has been processed.
     Ack,
-    /// A command telling the connection task to hang up, is issues after the client has sent a
-    /// QUIT command.
+    /// A sequence of messages that should be sent back to the client, in order.
+    Reply(Vec<String>),
+    /// The connection is refused, for example because the client is banned. The reason is sent
+    /// to the client before hanging up.
+    Refuse(String),
+    /// A line of a file transfer that the connection task should pass on to the low priority
+    /// `files` queue of the recipient, waiting for space in the queue. The connection task doesn't
+    /// pass on the sender's next line of a file transfer until there is space, which stops it
+    /// sending faster than the recipient can receive.
+    Relay {
+        to: mpsc::Sender<String>,
+        line: String,
+        /// The id of the file transfer, which is cancelled if the recipient stops receiving.
+        transfer: u64,
+    },
+    /// The connection has become a link to another server. The connection task stops forwarding
+    /// broadcasts meant for clients and sends the other server the lines from the receiver
+    /// instead, hanging up once the server actor drops the link.
+    Linked(mpsc::Receiver<String>),
+    /// A command telling the connection task to hang up, is issued after the client has sent a
+    /// QUIT command or an operator has sent a KILL command.
     HangUp,
 }
@@ -1,7 +1,22 @@
+pub mod admin;
+mod audit;
+mod ban;
+mod channel;
+mod client;
+pub mod config;
 mod connection;
+mod discovery;
+mod history;
 mod internal_message;
+pub mod limits;
+mod link;
+pub mod logging;
+mod mdns;
+mod metrics;
+mod reload;
 mod run;
 mod server;
+mod transfer;
 
 pub use run::run;
 
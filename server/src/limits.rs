@@ -0,0 +1,121 @@
+//! Limits on the number and rate of connections accepted by the server, on the rate of messages
+//! from a single connection, and on the files relayed for a single connection.
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The maximum number of clients connected at once.
+    pub max_clients: usize,
+    /// The maximum number of clients connected at once from a single IP address.
+    pub max_per_ip: usize,
+    /// The maximum number of connections accepted from a single IP address per minute.
+    pub accepts_per_minute: u32,
+    /// The maximum size in bytes of a file sent through the server.
+    pub max_file_size: u64,
+    /// The maximum number of files a single client can be sending at once, clients can't send
+    /// files through the server if this is 0.
+    pub max_transfers: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_clients: 1024,
+            max_per_ip: 16,
+            accepts_per_minute: 60,
+            max_file_size: 16 * 1024 * 1024,
+            max_transfers: 4,
+        }
+    }
+}
+
+/// A token bucket per IP address used to limit the rate at which connections are accepted.
+#[derive(Debug, Default)]
+pub(crate) struct AcceptRateLimiter {
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl AcceptRateLimiter {
+    /// Takes a token from the bucket for `ip`, returning `false` if the bucket is empty and the
+    /// connection should be refused.
+    pub fn try_accept(&mut self, ip: IpAddr, per_minute: u32, now: Instant) -> bool {
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+
+        // Forget addresses whose buckets have refilled so the map doesn't grow without bound.
+        if self.buckets.len() > 1024 {
+            self.buckets.retain(|_, bucket| {
+                bucket.refill(capacity, per_second, now);
+                bucket.tokens < capacity
+            });
+        }
+
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::full(capacity, now))
+            .try_take(capacity, per_second, now)
+    }
+}
+
+/// A bucket of tokens that refills at a steady rate up to its capacity, each permitted action
+/// takes a token.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn full(capacity: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: f64, per_second: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * per_second).min(capacity);
+        self.last_refill = now;
+    }
+
+    /// Takes a token, returning `false` if the bucket is empty and the action should not be
+    /// permitted.
+    pub fn try_take(&mut self, capacity: f64, per_second: f64, now: Instant) -> bool {
+        self.refill(capacity, per_second, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn accept_rate_limiter_refills() {
+        let mut limiter = AcceptRateLimiter::default();
+        let ip: IpAddr = "192.168.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.try_accept(ip, 2, now));
+        assert!(limiter.try_accept(ip, 2, now));
+        assert!(!limiter.try_accept(ip, 2, now));
+
+        // Other addresses have their own bucket.
+        assert!(limiter.try_accept("192.168.0.3".parse().unwrap(), 2, now));
+
+        // One token is added back every 30 seconds.
+        let later = now + Duration::from_secs(30);
+        assert!(limiter.try_accept(ip, 2, later));
+        assert!(!limiter.try_accept(ip, 2, later));
+    }
+}
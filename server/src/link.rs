@@ -0,0 +1,364 @@
+//! Links between servers, which join them into a single network.
+//!
+//! One server connects to the other on its usual port, as configured with `connect` in the
+//! [`LinkConfig`] of the link, and each side sends [`Command::Server`] with its own name and the
+//! password of the link. Once both sides have checked the other's name and password, each sends
+//! [`Command::Introduce`] for every user it knows of, and from then on relays the changes to its
+//! users and the messages they send to each other:
+//!
+//! ```text
+//! SERVER floor1.example.lan correct-horse
+//! :floor1.example.lan INTRODUCE olly 1700000000
+//! :olly NICK oliver
+//! :oliver AWAY :lunch
+//! :oliver MSG bob :back soon
+//! :oliver MSG :hello everyone
+//! :oliver QUIT
+//! ```
+//!
+//! Each server passes on what it receives over one link to its other links, so links must form a
+//! tree. Channels with the same name on linked servers are joined, messages sent to a channel
+//! reach its members on every server, but membership isn't shared.
+//!
+//! Message ids are only meaningful on the server that gave them, so messages are relayed without
+//! their tags and each server gives them ids of its own. Everything that refers to a message by
+//! id stays on the server of the client that sent it: a reply reaches other servers as a plain
+//! message, and EDIT, DELETE, REACT and UNREACT aren't relayed. Typing notifications aren't
+//! relayed either, they are only shown to clients of the same server.
+//!
+//! Nicks are shared across the network. When a user is introduced with a nick that is already in
+//! use, the user that registered first keeps the nick and the other is renamed, see
+//! [`keeps_nick`] and [`collision_nick`]. Every server applies the same rule so they all agree on
+//! who was renamed without sending anything further. When a link is lost the users on the other
+//! side of it are removed, and their removal is passed on to the servers still linked.
+//!
+//! [`Command::Server`]: protocol::command::Command::Server
+//! [`Command::Introduce`]: protocol::command::Command::Introduce
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use protocol::nick;
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time::sleep,
+};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    config::LinkConfig,
+    connection,
+    internal_message::{InternalMessage, Response},
+    metrics::Metrics,
+};
+
+/// How long to wait before connecting to a server again after a link is lost or can't be made.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// The number of lines waiting to be sent to another server before the link is dropped for not
+/// keeping up.
+pub(crate) const LINK_QUEUE: usize = 1024;
+
+/// The links to other servers and the users known through them.
+#[derive(Debug, Default)]
+pub(crate) struct Links {
+    links: HashMap<SocketAddr, Link>,
+    users: Vec<RemoteUser>,
+}
+
+/// A connection to another server.
+#[derive(Debug)]
+pub(crate) struct Link {
+    /// The server name of the other server.
+    pub name: String,
+    /// Used to send lines to the other server.
+    pub send: mpsc::Sender<String>,
+    /// Whether the other server has authenticated, nothing is sent over the link until it has.
+    pub authenticated: bool,
+}
+
+/// A user connected to another server in the network.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RemoteUser {
+    pub nick: String,
+    /// The name of the server the user is connected to.
+    pub server: String,
+    /// The address of the link the user is known through.
+    pub link: SocketAddr,
+    /// When the user connected as seconds since the unix epoch.
+    pub registered: u64,
+    pub away: Option<String>,
+}
+
+impl Link {
+    /// Sends a line to the other server, returning `false` if the queue of lines is full. A
+    /// dropped line would leave the servers disagreeing about the network, so the link has to be
+    /// dropped too.
+    pub fn send_line(&self, line: String) -> bool {
+        !matches!(self.send.try_send(line), Err(TrySendError::Full(_)))
+    }
+}
+
+impl Links {
+    pub fn get(&self, addr: SocketAddr) -> Option<&Link> {
+        self.links.get(&addr)
+    }
+
+    pub fn get_mut(&mut self, addr: SocketAddr) -> Option<&mut Link> {
+        self.links.get_mut(&addr)
+    }
+
+    pub fn insert(&mut self, addr: SocketAddr, link: Link) {
+        self.links.insert(addr, link);
+    }
+
+    /// Whether the server with the given name is already part of the network, either linked
+    /// directly or through another server.
+    pub fn is_linked(&self, name: &str) -> bool {
+        self.links
+            .values()
+            .any(|link| link.authenticated && link.name == name)
+            || self.users.iter().any(|user| user.server == name)
+    }
+
+    /// The links that have been authenticated, other than the one at `except`.
+    pub fn authenticated(
+        &self,
+        except: Option<SocketAddr>,
+    ) -> impl Iterator<Item = (SocketAddr, &Link)> {
+        self.links
+            .iter()
+            .filter(move |(addr, link)| link.authenticated && Some(**addr) != except)
+            .map(|(addr, link)| (*addr, link))
+    }
+
+    /// Returns the remote user with the given nick, ignoring case.
+    pub fn find_user(&self, nick: &str) -> Option<&RemoteUser> {
+        self.users.iter().find(|user| nick::eq(&user.nick, nick))
+    }
+
+    pub fn find_user_mut(&mut self, nick: &str) -> Option<&mut RemoteUser> {
+        self.users
+            .iter_mut()
+            .find(|user| nick::eq(&user.nick, nick))
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &RemoteUser> {
+        self.users.iter()
+    }
+
+    pub fn add_user(&mut self, user: RemoteUser) {
+        self.users.push(user);
+    }
+
+    pub fn remove_user(&mut self, nick: &str) -> Option<RemoteUser> {
+        let i = self
+            .users
+            .iter()
+            .position(|user| nick::eq(&user.nick, nick))?;
+        Some(self.users.remove(i))
+    }
+
+    /// Removes a link along with the users known through it.
+    pub fn remove(&mut self, addr: SocketAddr) -> Option<(Link, Vec<RemoteUser>)> {
+        let link = self.links.remove(&addr)?;
+        let (lost, kept) = self.users.drain(..).partition(|user| user.link == addr);
+        self.users = kept;
+        Some((link, lost))
+    }
+}
+
+/// Whether user `a` keeps a nick that user `b` also has, given when each user registered and the
+/// name of the server each is connected to. The user that registered first keeps the nick, ties
+/// go to the user on the server whose name sorts first.
+pub(crate) fn keeps_nick(a: (u64, &str), b: (u64, &str)) -> bool {
+    a < b
+}
+
+/// The nick given to the user connected to `server` that lost a collision over `nick`, which is
+/// the nick followed by the first label of the server name.
+pub(crate) fn collision_nick(nick: &str, server: &str) -> String {
+    let label: String = server
+        .split('.')
+        .next()
+        .unwrap_or(server)
+        .chars()
+        .take(nick::MAX_LEN / 2)
+        .collect();
+    let nick: String = nick
+        .chars()
+        .take(nick::MAX_LEN - label.chars().count() - 1)
+        .collect();
+    format!("{}-{}", nick, label)
+}
+
+/// Connects to the server named in `link`, handling the link like any other connection until it
+/// is lost, and then connecting again.
+#[instrument(name = "link", skip_all, fields(name = %link.name))]
+pub(crate) async fn connect(
+    link: LinkConfig,
+    addr: SocketAddr,
+    tx: mpsc::Sender<InternalMessage>,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(socket) => {
+                let (send, link_recv) = mpsc::channel(LINK_QUEUE);
+                // Lines are only sent to the other server through `send`.
+                let (_, direct_recv) = mpsc::channel::<Response>(1);
+                let (_, files_recv) = mpsc::channel::<String>(1);
+                let (once_send, once_recv) = oneshot::channel();
+                let _ = tx
+                    .send(InternalMessage::Link {
+                        addr,
+                        name: link.name.clone(),
+                        send,
+                        respond: once_send,
+                    })
+                    .await;
+
+                match once_recv.await {
+                    Ok(Response::Refuse(reason)) => {
+                        warn!(%addr, %reason, "refused link");
+                    }
+                    Ok(_) => {
+                        info!(%addr, "connected to server");
+                        let receivers = connection::Receivers {
+                            broadcast: None,
+                            direct: direct_recv,
+                            files: files_recv,
+                            hang_up: None,
+                            link: Some(link_recv),
+                        };
+                        connection::handle_connection(
+                            socket,
+                            addr,
+                            tx.clone(),
+                            receivers,
+                            metrics.clone(),
+                        )
+                        .await;
+                    }
+                    // The server actor has stopped.
+                    Err(_) => return,
+                }
+            }
+            Err(e) => warn!(%addr, error = %e, "failed to connect to server"),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+        net::{
+            tcp::{OwnedReadHalf, OwnedWriteHalf},
+            TcpListener,
+        },
+        time::timeout,
+    };
+
+    use crate::config::Config;
+
+    type ServerLines = Lines<BufReader<OwnedReadHalf>>;
+
+    /// Starts a server listening on a free port on localhost, returning its address.
+    async fn start(name: &str, peer: &str, connect: Option<SocketAddr>) -> SocketAddr {
+        let listen = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Config {
+            listen,
+            server_name: name.to_owned(),
+            discovery: false,
+            mdns: false,
+            links: vec![LinkConfig {
+                name: peer.to_owned(),
+                password: "secret".to_owned(),
+                connect,
+            }],
+            ..Config::default()
+        };
+        tokio::spawn(crate::run(config));
+        listen
+    }
+
+    /// Connects to a server and registers a nick, returning the lines sent by the server and the
+    /// half of the connection used to send lines to it.
+    async fn register(addr: SocketAddr, nick: &str) -> (ServerLines, OwnedWriteHalf) {
+        let stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => break stream,
+                Err(_) => sleep(Duration::from_millis(20)).await,
+            }
+        };
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write
+            .write_all(format!("NICK {}\r\n", nick).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            "NOMOTD",
+            next_line(&mut lines).await.split(' ').nth(1).unwrap()
+        );
+        (lines, write)
+    }
+
+    async fn next_line(lines: &mut ServerLines) -> String {
+        timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn servers_link_over_tcp() {
+        let floor1 = start("floor1.lan", "floor2.lan", None).await;
+        let floor2 = start("floor2.lan", "floor1.lan", Some(floor1)).await;
+        let (mut olly, _olly_write) = register(floor1, "olly").await;
+        let (mut bob, mut bob_write) = register(floor2, "bob").await;
+
+        // Wait for the link to be made and olly to be introduced to floor2.lan.
+        loop {
+            bob_write.write_all(b"NAMES\r\n").await.unwrap();
+            let mut names = Vec::new();
+            loop {
+                match next_line(&mut bob).await.as_str() {
+                    "ENDOFNAMES" => break,
+                    line => names.push(line.to_owned()),
+                }
+            }
+            if names.contains(&"NAMREPLY olly H".to_owned()) {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        bob_write.write_all(b"MSG olly :hi\r\n").await.unwrap();
+        assert_eq!(":bob MSG olly id=1 :hi", next_line(&mut olly).await);
+    }
+
+    #[test]
+    fn nick_collisions_are_resolved_by_registration_then_server() {
+        assert!(keeps_nick((100, "floor2.lan"), (200, "floor1.lan")));
+        assert!(keeps_nick((100, "floor1.lan"), (100, "floor2.lan")));
+        assert!(!keeps_nick((100, "floor2.lan"), (100, "floor1.lan")));
+
+        assert_eq!("olly-floor2", collision_nick("olly", "floor2.example.lan"));
+        let long = "o".repeat(nick::MAX_LEN);
+        let renamed = collision_nick(&long, "floor2.example.lan");
+        assert_eq!(nick::MAX_LEN, renamed.chars().count());
+        assert!(renamed.ends_with("-floor2"));
+        assert!(nick::is_valid(&renamed));
+    }
+}
@@ -0,0 +1,51 @@
+//! Logging setup for the server binary.
+use std::{fmt, str::FromStr};
+
+use tracing_subscriber::EnvFilter;
+
+use crate::BoxedError;
+
+/// The format logs are written to stdout in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, multi-line output.
+    Pretty,
+    /// Newline delimited JSON, for ingestion by log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unrecognized log format: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Pretty => f.write_str("pretty"),
+            LogFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// Installs the global tracing subscriber. `level` is used as the filter unless the `RUST_LOG`
+/// environment variable is set, in which case that takes precedence.
+pub fn init(format: LogFormat, level: &str) -> Result<(), BoxedError> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(level)?,
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Pretty => subscriber.pretty().try_init(),
+        LogFormat::Json => subscriber.json().try_init(),
+    }
+}
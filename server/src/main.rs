@@ -1,4 +1,33 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use server::{
+    config::Config,
+    logging::{self, LogFormat},
+};
+
+/// A chat server for the local network.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Path to the TOML config file, the defaults are used if it is not given.
+    config: Option<PathBuf>,
+    /// The format of the logs, either `pretty` or `json`.
+    #[arg(long, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// The minimum level of the logs, the `RUST_LOG` environment variable takes precedence.
+    #[arg(long, default_value = "info")]
+    log_level: String,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), server::BoxedError> {
-    server::run().await
+    let args = Args::parse();
+    logging::init(args.log_format, &args.log_level)?;
+
+    let config = match args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    server::run(config).await
 }
@@ -0,0 +1,123 @@
+//! Advertises the server as a DNS-SD service over mDNS, see [`protocol::discovery`].
+use std::net::SocketAddr;
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+use protocol::discovery::{SERVICE_TYPE, TXT_CAPABILITIES, TXT_NAME, TXT_TLS, TXT_VERSION};
+
+use crate::{config::Config, server::CAPABILITIES};
+
+/// Registers the server listening on `addr` with mDNS. The service is advertised until the
+/// returned daemon is shut down.
+pub(crate) fn advertise(config: &Config, addr: SocketAddr) -> mdns_sd::Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    // Loopback interfaces are skipped unless enabled, which would leave a server that only
+    // listens on loopback unadvertised.
+    if addr.ip().is_loopback() {
+        daemon.enable_interface(IfKind::LoopbackV4)?;
+    }
+    daemon.register(service_info(config, addr, &hostname())?)?;
+    Ok(daemon)
+}
+
+/// The name of the machine without its domain, or `lanchat` if it has none.
+fn hostname() -> String {
+    let hostname = gethostname::gethostname();
+    let hostname = hostname.to_string_lossy();
+    match hostname.split('.').next() {
+        Some(label) if !label.is_empty() => label.to_owned(),
+        _ => "lanchat".to_owned(),
+    }
+}
+
+/// Describes the server as a service on the machine named `hostname`. The instance is named after
+/// the machine and the port, which is unique even when several servers share a server name or a
+/// machine. The server name is given in the TXT records.
+fn service_info(config: &Config, addr: SocketAddr, hostname: &str) -> mdns_sd::Result<ServiceInfo> {
+    let instance = format!("{}-{}", hostname, addr.port());
+    let properties = [
+        (TXT_NAME, config.server_name.clone()),
+        (TXT_VERSION, env!("CARGO_PKG_VERSION").to_owned()),
+        (TXT_CAPABILITIES, CAPABILITIES.join(",")),
+        (TXT_TLS, "false".to_owned()),
+    ];
+    let host = format!("{}.local.", hostname);
+
+    if addr.ip().is_unspecified() {
+        // Listening on every interface, so advertise every address the host has.
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host,
+            "",
+            addr.port(),
+            &properties[..],
+        )?;
+        Ok(info.enable_addr_auto())
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host,
+            addr.ip(),
+            addr.port(),
+            &properties[..],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn advertised_server_is_found_by_browsing() {
+        let config = Config {
+            server_name: "mdnstest.example.lan".to_owned(),
+            ..Config::default()
+        };
+        // Hold a port chosen by the OS so that the service doesn't clash with a real server or
+        // another run of this test.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let daemon = advertise(&config, addr).unwrap();
+
+        let servers = client::mdns::browse(Duration::from_secs(3)).await.unwrap();
+        let _ = daemon.shutdown();
+        let server = servers
+            .iter()
+            .find(|server| server.addr == addr)
+            .expect("server not found");
+        assert_eq!("mdnstest.example.lan", server.name);
+        assert_eq!(vec!["typing"], server.capabilities);
+    }
+
+    #[test]
+    fn service_describes_the_server() {
+        let config = Config {
+            server_name: "chat.example.lan".to_owned(),
+            ..Config::default()
+        };
+        let info = service_info(&config, "0.0.0.0:3000".parse().unwrap(), "box1").unwrap();
+
+        assert_eq!("box1-3000._lanchat._tcp.local.", info.get_fullname());
+        assert_eq!("box1.local.", info.get_hostname());
+        assert_eq!(3000, info.get_port());
+        assert!(info.is_addr_auto());
+        assert_eq!(
+            Some("chat.example.lan"),
+            info.get_property_val_str(TXT_NAME)
+        );
+        assert_eq!(Some("typing"), info.get_property_val_str(TXT_CAPABILITIES));
+        assert_eq!(Some("false"), info.get_property_val_str(TXT_TLS));
+    }
+
+    #[test]
+    fn servers_on_one_machine_are_distinct_services() {
+        let config = Config::default();
+        let first = service_info(&config, "0.0.0.0:3000".parse().unwrap(), "box1").unwrap();
+        let second = service_info(&config, "0.0.0.0:3001".parse().unwrap(), "box1").unwrap();
+        assert_ne!(first.get_fullname(), second.get_fullname());
+        assert_eq!(first.get_hostname(), second.get_hostname());
+    }
+}
@@ -0,0 +1,243 @@
+//! Server health metrics, served in the Prometheus text format.
+use std::{
+    fmt::Write as _,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use protocol::codec::LanChatCodecError;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tracing::{info, instrument, warn};
+
+use crate::internal_message::InternalMessage;
+
+/// Counters and gauges shared between the tasks of the server.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    connected_clients: AtomicU64,
+    messages: AtomicU64,
+    broadcast_lagged: AtomicU64,
+    codec_errors: [AtomicU64; CODEC_ERROR_KINDS.len()],
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+/// The `kind` label for each variant of `LanChatCodecError`.
+const CODEC_ERROR_KINDS: [&str; 4] = ["lf_without_cr", "max_length_exceeded", "io", "parse"];
+
+impl Metrics {
+    pub fn client_connected(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn message_received(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn broadcast_lagged(&self) {
+        self.broadcast_lagged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn codec_error(&self, e: &LanChatCodecError) {
+        let index = match e {
+            LanChatCodecError::LfWithoutCr => 0,
+            LanChatCodecError::MaxLengthExceeded => 1,
+            LanChatCodecError::Io(_) => 2,
+            LanChatCodecError::ParseError(_) => 3,
+        };
+        self.codec_errors[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text format. The depth of the queue of messages
+    /// waiting for the server actor is sampled by the caller.
+    pub fn render(&self, queue_depth: usize) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        metric(
+            "lanchat_connected_clients",
+            "gauge",
+            "Number of connected clients.",
+            &[("", self.connected_clients.load(Ordering::Relaxed))],
+        );
+        metric(
+            "lanchat_messages_total",
+            "counter",
+            "Messages received from clients.",
+            &[("", self.messages.load(Ordering::Relaxed))],
+        );
+        metric(
+            "lanchat_broadcast_lagged_total",
+            "counter",
+            "Times a connection fell behind the broadcast channel and skipped messages.",
+            &[("", self.broadcast_lagged.load(Ordering::Relaxed))],
+        );
+        let labels: Vec<String> = CODEC_ERROR_KINDS
+            .iter()
+            .map(|kind| format!("{{kind=\"{}\"}}", kind))
+            .collect();
+        let codec_errors: Vec<(&str, u64)> = labels
+            .iter()
+            .zip(&self.codec_errors)
+            .map(|(labels, count)| (labels.as_str(), count.load(Ordering::Relaxed)))
+            .collect();
+        metric(
+            "lanchat_codec_errors_total",
+            "counter",
+            "Errors decoding messages from clients by kind.",
+            &codec_errors,
+        );
+        metric(
+            "lanchat_internal_queue_depth",
+            "gauge",
+            "Messages waiting to be processed by the server actor.",
+            &[("", queue_depth as u64)],
+        );
+        metric(
+            "lanchat_bytes_received_total",
+            "counter",
+            "Bytes received from clients.",
+            &[("", self.bytes_received.load(Ordering::Relaxed))],
+        );
+        metric(
+            "lanchat_bytes_sent_total",
+            "counter",
+            "Bytes sent to clients.",
+            &[("", self.bytes_sent.load(Ordering::Relaxed))],
+        );
+
+        out
+    }
+}
+
+/// Serves the metrics over HTTP at `/metrics`.
+#[instrument(name = "metrics", skip_all)]
+pub(crate) async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    tx: mpsc::Sender<InternalMessage>,
+) {
+    loop {
+        let (mut socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(error = %e, "failed to accept metrics connection");
+                continue;
+            }
+        };
+
+        let queue_depth = tx.max_capacity() - tx.capacity();
+        let body = metrics.render(queue_depth);
+        tokio::spawn(async move {
+            // Only the request line is needed, the rest of the request is ignored.
+            let mut buf = [0; 1024];
+            let n = match socket.read(&mut buf).await {
+                Ok(n) => n,
+                Err(_) => return,
+            };
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let response = if request.starts_with("GET /metrics ") {
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                info!(%addr, "metrics request for unknown path");
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned()
+            };
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// Wraps a stream to count the bytes read from and written to it.
+#[derive(Debug)]
+pub(crate) struct Metered<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, metrics: Arc<Metrics>) -> Metered<S> {
+        Metered { inner, metrics }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.metrics
+            .bytes_received
+            .fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.metrics
+                .bytes_sent
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::default();
+        metrics.client_connected();
+        metrics.client_connected();
+        metrics.client_disconnected();
+        metrics.codec_error(&LanChatCodecError::MaxLengthExceeded);
+
+        let rendered = metrics.render(3);
+        assert!(rendered.contains("\nlanchat_connected_clients 1\n"));
+        assert!(rendered.contains("\nlanchat_codec_errors_total{kind=\"max_length_exceeded\"} 1\n"));
+        assert!(rendered.contains("\nlanchat_codec_errors_total{kind=\"parse\"} 0\n"));
+        assert!(rendered.contains("\nlanchat_internal_queue_depth 3\n"));
+    }
+}
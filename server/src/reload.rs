@@ -0,0 +1,83 @@
+//! Reloading the configuration while the server is running.
+//!
+//! A reload is triggered by sending the server SIGHUP or with the admin RELOAD command. The config
+//! file is re-read and validated, along with the ban list, audit log and MOTD it names, before being
+//! handed to the server actor. Settings that can only be applied on startup are reported back so
+//! the operator knows a restart is needed.
+use std::path::{Path, PathBuf};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit::AuditLog,
+    ban::BanList,
+    config::Config,
+    internal_message::{AdminResponse, InternalMessage},
+};
+
+/// Re-reads the config at `path` and sends it to the server actor, returning the lines describing
+/// the outcome of the reload.
+pub(crate) async fn reload(
+    path: Option<&Path>,
+    tx: &mpsc::Sender<InternalMessage>,
+) -> AdminResponse {
+    // File IO happens here rather than in the server actor to avoid blocking it.
+    let path = path
+        .ok_or_else(|| "The server was not started with a config file".to_owned())?
+        .to_owned();
+    let (config, audit, bans, motd) = tokio::task::spawn_blocking(move || {
+        let config = Config::load(path).map_err(|e| format!("Failed to load config: {}", e))?;
+        let audit = AuditLog::open(config.audit_log.as_deref())
+            .map_err(|e| format!("Failed to open audit log: {}", e))?;
+        // Without a ban list file the bans only exist in memory, so they are kept as they are.
+        let bans = match &config.ban_list {
+            Some(path) => Some(
+                BanList::load(Some(path)).map_err(|e| format!("Failed to load ban list: {}", e))?,
+            ),
+            None => None,
+        };
+        let motd = config
+            .load_motd()
+            .map_err(|e| format!("Failed to read MOTD: {}", e))?;
+        Ok::<_, String>((config, audit, bans, motd))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    let (respond, response) = oneshot::channel();
+    tx.send(InternalMessage::Reload {
+        config: Box::new(config),
+        audit,
+        bans,
+        motd,
+        respond,
+    })
+    .await
+    .map_err(|_| "The server has stopped".to_owned())?;
+    response
+        .await
+        .map_err(|_| "The server has stopped".to_owned())?
+}
+
+/// Reloads the config at `path` each time the process receives SIGHUP.
+pub(crate) async fn on_hangup(path: Option<PathBuf>, tx: mpsc::Sender<InternalMessage>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!(error = %e, "failed to listen for SIGHUP");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("received SIGHUP");
+        // The server actor logs the outcome of a successful reload.
+        if let Err(e) = reload(path.as_deref(), &tx).await {
+            warn!(error = %e, "failed to reload config");
+        }
+    }
+}
@@ -1,25 +1,149 @@
-use tokio::sync::{broadcast, mpsc};
-use tokio::{net::TcpListener, sync::oneshot};
+use std::{net::SocketAddr, sync::Arc};
 
-use crate::{connection, internal_message::InternalMessage, server, BoxedError};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{info, instrument, warn};
 
-pub async fn run() -> Result<(), BoxedError> {
-    let listener = TcpListener::bind("0.0.0.0:3000").await?;
+use crate::{
+    admin,
+    audit::AuditLog,
+    ban::BanList,
+    channel::TopicList,
+    config::Config,
+    connection, discovery,
+    internal_message::{InternalMessage, Response},
+    link, mdns,
+    metrics::{self, Metrics},
+    reload, server, BoxedError,
+};
+
+#[instrument(name = "run", skip_all, fields(listen = %config.listen))]
+pub async fn run(config: Config) -> Result<(), BoxedError> {
+    let listener = TcpListener::bind(config.listen).await?;
+    let audit = AuditLog::open(config.audit_log.as_deref())?;
+    let bans = BanList::load(config.ban_list.as_deref())?;
+    let topics = TopicList::load(config.topic_list.as_deref())?;
+    let motd = config.load_motd()?;
 
     let (b_send, _) = broadcast::channel::<String>(8);
     let (tx, rx) = mpsc::channel::<InternalMessage>(128);
+    info!("listening for connections");
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(metrics_listen) = config.metrics_listen {
+        let metrics_listener = TcpListener::bind(metrics_listen).await?;
+        info!(%metrics_listen, "serving metrics");
+        tokio::spawn(metrics::serve(
+            metrics_listener,
+            metrics.clone(),
+            tx.clone(),
+        ));
+    }
+    if let Some(admin_socket) = &config.admin_socket {
+        let admin_listener = admin::bind(admin_socket)?;
+        info!(admin_socket = %admin_socket.display(), "listening for admin commands");
+        tokio::spawn(admin::serve(
+            admin_listener,
+            tx.clone(),
+            config.path.clone(),
+        ));
+    }
+
+    // The service stays advertised for as long as the daemon is kept.
+    let _mdns = if config.mdns {
+        match mdns::advertise(&config, listener.local_addr()?) {
+            Ok(daemon) => {
+                info!("advertising with mdns");
+                Some(daemon)
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to advertise with mdns");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if config.discovery {
+        let discovery_socket = discovery::bind(config.listen).await?;
+        info!("answering discovery requests");
+        tokio::spawn(discovery::serve(discovery_socket, tx.clone()));
+    }
+
+    for link_config in &config.links {
+        if let Some(addr) = link_config.connect {
+            tokio::spawn(link::connect(
+                link_config.clone(),
+                addr,
+                tx.clone(),
+                metrics.clone(),
+            ));
+        }
+    }
+
+    tokio::spawn(reload::on_hangup(config.path.clone(), tx.clone()));
 
     let server_bcast = b_send.clone();
-    tokio::spawn(async move { server::run_server(rx, server_bcast).await });
+    tokio::spawn(async move {
+        server::run_server(rx, server_bcast, config, audit, bans, topics, motd).await
+    });
 
     loop {
-        let (socket, addr) = listener.accept().await?;
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // The server actor has stopped.
+            _ = tx.closed() => return Ok(()),
+        };
+        tokio::spawn(accept(
+            socket,
+            addr,
+            tx.clone(),
+            b_send.clone(),
+            metrics.clone(),
+        ));
+    }
+}
 
-        let b_recv = b_send.subscribe();
-        let tx = tx.clone();
+/// Checks with the server actor that a newly accepted connection is allowed before handling it,
+/// so that the accept loop doesn't wait on the server actor.
+async fn accept(
+    socket: TcpStream,
+    addr: SocketAddr,
+    tx: mpsc::Sender<InternalMessage>,
+    b_send: broadcast::Sender<String>,
+    metrics: Arc<Metrics>,
+) {
+    let (direct_send, direct_recv) = mpsc::channel::<Response>(32);
+    let (files_send, files_recv) = mpsc::channel::<String>(8);
+    let (hang_up_send, hang_up_recv) = oneshot::channel();
+    let (once_send, once_recv) = oneshot::channel();
+    let _ = tx
+        .send(InternalMessage::Connect {
+            addr,
+            send: direct_send,
+            files: files_send,
+            hang_up: hang_up_send,
+            respond: once_send,
+        })
+        .await;
 
-        tokio::spawn(async move {
-            connection::handle_connection(socket, addr, tx, b_recv).await;
-        });
+    match once_recv.await {
+        Ok(Response::Refuse(reason)) => {
+            warn!(%addr, %reason, "refused connection");
+            connection::refuse_connection(socket, reason).await;
+        }
+        Ok(_) => {
+            info!(%addr, "accepted connection");
+            let receivers = connection::Receivers {
+                broadcast: Some(b_send.subscribe()),
+                direct: direct_recv,
+                files: files_recv,
+                hang_up: Some(hang_up_recv),
+                link: None,
+            };
+            connection::handle_connection(socket, addr, tx, receivers, metrics).await;
+        }
+        // The server actor has stopped.
+        Err(_) => {}
     }
 }
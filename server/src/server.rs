@@ -1,34 +1,3263 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
 
-use protocol::{command::Command, message::Prefix};
-use tokio::sync::{broadcast::Sender, mpsc::Receiver};
+use protocol::{
+    command::{is_valid_reaction, Command, MsgTags, CAP_TYPING},
+    file,
+    message::{LanChatMessage, Prefix},
+    nick,
+};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{info, instrument, warn};
 
-use crate::internal_message::{InternalMessage, Response};
+use crate::{
+    audit::AuditLog,
+    ban::{Ban, BanList, BanMask},
+    channel::{is_channel_name, Channel, Topic, TopicList},
+    client::{unix_secs, Client},
+    config::Config,
+    history::{History, StoredMessage},
+    internal_message::{AdminResponse, InternalMessage, Response},
+    limits::AcceptRateLimiter,
+    link::{collision_nick, keeps_nick, Link, Links, RemoteUser, LINK_QUEUE},
+    transfer::Transfers,
+};
 
-pub async fn run_server(mut recv: Receiver<InternalMessage>, msg_broadcast: Sender<String>) {
-    let mut prefixes: HashMap<SocketAddr, Prefix> = HashMap::new();
+/// The maximum number of messages sent in reply to HISTORY.
+const HISTORY_REPLAY_LEN: usize = 50;
+
+/// The optional protocol features the server supports, requested by clients with CAP.
+pub(crate) const CAPABILITIES: &[&str] = &[CAP_TYPING];
+
+/// The maximum number of different reactions to a single message, which keeps messages replayed
+/// with their reactions within the maximum line length.
+const MAX_REACTIONS: usize = 20;
+
+#[instrument(name = "server", skip_all)]
+pub async fn run_server(
+    mut recv: mpsc::Receiver<InternalMessage>,
+    msg_broadcast: broadcast::Sender<String>,
+    config: Config,
+    audit: AuditLog,
+    bans: BanList,
+    topics: TopicList,
+    motd: Vec<String>,
+) {
+    let mut server = Server::new(msg_broadcast, config, audit, bans);
+    server.topics = topics;
+    server.motd = motd;
+
+    while let Some(internal_msg) = recv.recv().await {
+        match internal_msg {
+            InternalMessage::Connect {
+                addr,
+                send,
+                files,
+                hang_up,
+                respond,
+            } => {
+                let response = server.connect(addr, send, files, hang_up);
+                let _ = respond.send(response);
+            }
+            InternalMessage::Link {
+                addr,
+                name,
+                send,
+                respond,
+            } => {
+                let response = server.link(addr, name, send);
+                let _ = respond.send(response);
+            }
+            InternalMessage::Message { addr, msg, respond } => {
+                let response = server.handle_message(addr, msg);
+                let _ = respond.send(response);
+            }
+            InternalMessage::Disconnect { addr } => server.disconnect(addr),
+            InternalMessage::ListConnections { respond } => {
+                let _ = respond.send(Ok(server.list_connections()));
+            }
+            InternalMessage::Kick {
+                nick,
+                reason,
+                respond,
+            } => {
+                let _ = respond.send(server.admin_kick(nick, reason));
+            }
+            InternalMessage::Notice { text, respond } => {
+                server.notice(text);
+                let _ = respond.send(Ok(Vec::new()));
+            }
+            InternalMessage::Reload {
+                config,
+                audit,
+                bans,
+                motd,
+                respond,
+            } => {
+                let _ = respond.send(Ok(server.reload(*config, audit, bans, motd)));
+            }
+            InternalMessage::Stats { respond } => {
+                let _ = respond.send(Ok(server.stats()));
+            }
+            InternalMessage::Discover { respond } => {
+                let _ = respond.send(server.announce());
+            }
+        }
+    }
+}
+
+/// State owned by the server actor.
+struct Server {
+    clients: HashMap<SocketAddr, Client>,
+    channels: HashMap<String, Channel>,
+    /// The topics of channels, which outlive the channels themselves.
+    topics: TopicList,
+    msg_broadcast: broadcast::Sender<String>,
+    config: Config,
+    audit: AuditLog,
+    bans: BanList,
+    accept_rate: AcceptRateLimiter,
+    /// The lines of the message of the day.
+    motd: Vec<String>,
+    history: History,
+    /// Files being sent between clients through the server.
+    transfers: Transfers,
+    /// Other servers in the network and the users connected to them.
+    links: Links,
+}
+
+impl Server {
+    fn new(
+        msg_broadcast: broadcast::Sender<String>,
+        config: Config,
+        audit: AuditLog,
+        bans: BanList,
+    ) -> Server {
+        Server {
+            clients: HashMap::new(),
+            channels: HashMap::new(),
+            topics: TopicList::default(),
+            msg_broadcast,
+            audit,
+            bans,
+            accept_rate: AcceptRateLimiter::default(),
+            motd: Vec::new(),
+            history: History::new(config.history_len),
+            transfers: Transfers::new(),
+            links: Links::default(),
+            config,
+        }
+    }
+
+    /// Registers a newly accepted connection, or refuses it if the address is banned or the
+    /// connection limits have been reached.
+    fn connect(
+        &mut self,
+        addr: SocketAddr,
+        send: mpsc::Sender<Response>,
+        files: mpsc::Sender<String>,
+        hang_up: oneshot::Sender<Response>,
+    ) -> Response {
+        if let Some(ban) = self.bans.find_ip(addr.ip()) {
+            return Response::Refuse(banned(ban));
+        }
+
+        let limits = &self.config.limits;
+        if !self
+            .accept_rate
+            .try_accept(addr.ip(), limits.accepts_per_minute, Instant::now())
+        {
+            return Response::Refuse("Connecting too quickly, try again later".to_owned());
+        }
+        if self.clients.len() >= limits.max_clients {
+            return Response::Refuse("The server is full".to_owned());
+        }
+        let from_ip = self
+            .clients
+            .keys()
+            .filter(|client_addr| client_addr.ip() == addr.ip())
+            .count();
+        if from_ip >= limits.max_per_ip {
+            return Response::Refuse("Too many connections from your address".to_owned());
+        }
+
+        self.clients.insert(addr, Client::new(send, files, hang_up));
+        Response::Ack
+    }
+
+    fn disconnect(&mut self, addr: SocketAddr) {
+        if self.links.get(addr).is_some() {
+            return self.netsplit(addr);
+        }
+        let client = self.clients.remove(&addr);
+        if let Some(prefix) = client.as_ref().and_then(|client| client.prefix.clone()) {
+            let quit = LanChatMessage {
+                prefix: Some(prefix),
+                command: Command::Quit,
+            };
+            self.send_to_links(&quit.to_string(), None);
+        }
+        let prefix = client.map(|client| client.prefix_or_unknown());
+        // Tell the other side of any file transfers that they won't be completed.
+        for (id, other) in self.transfers.remove_client(addr) {
+            if let Some(other) = self.clients.get(&other) {
+                let cancel = LanChatMessage {
+                    prefix: prefix.clone(),
+                    command: Command::FileCancel(id),
+                };
+                other.send_line(cancel.to_string());
+            }
+        }
+        for channel in self.channels.values_mut() {
+            channel.members.remove(&addr);
+        }
+        self.channels.retain(|_, channel| !channel.is_abandoned());
+    }
+
+    fn handle_message(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        if self.links.get(addr).is_some() {
+            return self.link_message(addr, msg);
+        }
+        let client = match self.clients.get_mut(&addr) {
+            Some(client) => client,
+            // Messages are only processed for connected clients.
+            None => return Response::HangUp,
+        };
 
-    while let Some(InternalMessage {
-        addr,
-        mut msg,
-        respond,
-    }) = recv.recv().await
-    {
         match msg.command {
-            Command::Msg(_) => {
-                let prefix = prefixes.get(&addr).cloned().unwrap_or_else(|| Prefix {
-                    nick: "unknown".to_string(),
-                });
-                msg.prefix = Some(prefix);
-                let _ = msg_broadcast.send(msg.to_string());
+            Command::Msg { .. }
+            | Command::PrivMsg { .. }
+            | Command::Notice { .. }
+            | Command::Action { .. }
+            | Command::FileOffer { .. }
+            | Command::DirectOffer { .. }
+                if client.muted =>
+            {
+                Response::Reply(vec![reply(Command::ErrorReply(
+                    "You have been muted by an operator".to_owned(),
+                ))])
+            }
+            Command::Msg { .. }
+            | Command::Notice { target: None, .. }
+            | Command::Action { target: None, .. } => {
+                client.last_active = Instant::now();
+                msg.prefix = Some(client.prefix_or_unknown());
+                if let Err(error) = self.record(addr, &mut msg) {
+                    return Response::Reply(vec![error]);
+                }
+                let _ = self.msg_broadcast.send(msg.to_string());
+                self.send_to_links(&link_line(&msg), None);
+                Response::Ack
+            }
+            Command::PrivMsg { ref target, .. }
+            | Command::Notice {
+                target: Some(ref target),
+                ..
+            }
+            | Command::Action {
+                target: Some(ref target),
+                ..
+            } => {
+                client.last_active = Instant::now();
+                let target = target.clone();
+                msg.prefix = Some(client.prefix_or_unknown());
+                if is_channel_name(&target) {
+                    self.channel_message(addr, &target, msg)
+                } else {
+                    self.private_message(addr, &target, msg)
+                }
+            }
+            Command::Nick(nick) => self.nick(addr, nick),
+            Command::Away(ref away) => {
+                client.away = away.clone();
+                // Notify other clients of the change in presence.
+                if client.prefix.is_some() {
+                    msg.prefix = client.prefix.clone();
+                    let _ = self.msg_broadcast.send(msg.to_string());
+                    self.send_to_links(&msg.to_string(), None);
+                }
+                Response::Ack
+            }
+            Command::Join(channel) => self.join(addr, channel),
+            Command::Part(channel) => self.part(addr, &channel),
+            Command::Topic { channel, text } => self.topic(addr, &channel, text),
+            Command::Quit => {
+                self.disconnect(addr);
+                Response::HangUp
+            }
+            Command::Names(channel) => Response::Reply(self.names(channel.as_deref())),
+            Command::Who => Response::Reply(self.who()),
+            Command::Thread(id) => Response::Reply(self.thread(addr, id)),
+            Command::History(ref target) => Response::Reply(self.history(addr, target.as_deref())),
+            Command::Edit { .. } | Command::Delete(_) => self.amend(addr, msg),
+            Command::React { .. } | Command::Unreact { .. } => self.react(addr, msg),
+            Command::Cap(caps) => {
+                client.capabilities = caps
+                    .into_iter()
+                    .filter(|cap| CAPABILITIES.contains(&cap.as_str()))
+                    .collect();
+                let mut caps: Vec<_> = client.capabilities.iter().cloned().collect();
+                caps.sort();
+                Response::Reply(vec![reply(Command::Cap(caps))])
+            }
+            Command::Typing { ref target, .. } => {
+                // Typing notifications are ephemeral, so ones that can't be delivered are dropped
+                // rather than replied to with an error.
+                if !client.muted && client.try_typing() {
+                    let target = target.clone();
+                    msg.prefix = Some(client.prefix_or_unknown());
+                    self.typing(addr, target.as_deref(), &msg.to_string());
+                }
+                Response::Ack
+            }
+            Command::FileOffer { .. } | Command::DirectOffer { .. } => self.file_offer(addr, msg),
+            Command::FileAccept(_)
+            | Command::FileReject(_)
+            | Command::FileCancel(_)
+            | Command::DirectAccept { .. } => self.file_reply(addr, msg),
+            Command::FileChunk { .. } | Command::FileDone { .. } => self.file_data(addr, msg),
+            Command::Discover => Response::Reply(vec![reply(self.announce())]),
+            Command::Server { name, password } => self.accept_link(addr, name, password),
+            Command::Motd => Response::Reply(self.motd()),
+            Command::Whois(nick) => Response::Reply(self.whois(addr, &nick)),
+            Command::Oper { name, password } => self.oper(addr, &name, &password),
+            Command::Kick { .. } | Command::Kill { .. } | Command::Mute(_) | Command::Unmute(_) => {
+                self.moderate(addr, msg)
+            }
+            Command::Ban {
+                mask,
+                duration,
+                reason,
+            } => self.ban(addr, &mask, duration, reason),
+            Command::Unban(mask) => self.unban(addr, &mask),
+            Command::BanList => self.ban_list(addr),
+            // Replies are only sent from the server to clients.
+            Command::NamesReply { .. }
+            | Command::EndOfNames
+            | Command::WhoReply { .. }
+            | Command::EndOfWho
+            | Command::WhoisUser { .. }
+            | Command::WhoisChannels { .. }
+            | Command::WhoisAddr { .. }
+            | Command::EndOfWhois(_)
+            | Command::YoureOper
+            | Command::BanReply { .. }
+            | Command::EndOfBanList
+            | Command::MotdReply(_)
+            | Command::EndOfMotd
+            | Command::NoMotd
+            | Command::EndOfThread(_)
+            | Command::EndOfHistory
+            | Command::AwayReply { .. }
+            | Command::TopicReply { .. }
+            | Command::NoTopic(_)
+            | Command::Announce { .. }
+            | Command::ErrorReply(_) => Response::Ack,
+            // Users are only introduced by other servers.
+            Command::Introduce { .. } => Response::Ack,
+        }
+    }
+
+    /// Forwards a message, notice or action to a single client, replying to the sender with the
+    /// target's away message if they are away. Notices never get an away reply.
+    fn private_message(
+        &mut self,
+        addr: SocketAddr,
+        target: &str,
+        mut msg: LanChatMessage,
+    ) -> Response {
+        let target_addr = self.find(target);
+        if target_addr.is_none() && self.links.find_user(target).is_none() {
+            return Response::Reply(vec![no_such_nick(target)]);
+        }
+        if let Err(error) = self.record(addr, &mut msg) {
+            return Response::Reply(vec![error]);
+        }
+
+        let line = msg.to_string();
+        let away = match target_addr {
+            Some(target_addr) => {
+                let client = &self.clients[&target_addr];
+                client.send_line(line.clone());
+                client.away.clone()
+            }
+            // Users on other servers are reached over the link they are known through.
+            None => match self.links.find_user(target) {
+                Some(user) => {
+                    let (link, away) = (user.link, user.away.clone());
+                    self.send_to_link(link, link_line(&msg));
+                    away
+                }
+                None => None,
+            },
+        };
+
+        let mut replies = echo(&msg, line);
+        if let (false, Some(away)) = (matches!(msg.command, Command::Notice { .. }), away) {
+            replies.push(reply(Command::AwayReply {
+                nick: target.to_owned(),
+                msg: away.to_owned(),
+            }));
+        }
+        reply_or_ack(replies)
+    }
+
+    /// Forwards a message, notice or action to the other members of a channel, the sender must be
+    /// a member.
+    fn channel_message(
+        &mut self,
+        addr: SocketAddr,
+        target: &str,
+        mut msg: LanChatMessage,
+    ) -> Response {
+        match self.channels.get(target) {
+            Some(channel) if channel.members.contains(&addr) => {}
+            Some(_) => return Response::Reply(vec![not_on_channel(target)]),
+            None => return Response::Reply(vec![no_such_channel(target)]),
+        }
+        if let Err(error) = self.record(addr, &mut msg) {
+            return Response::Reply(vec![error]);
+        }
+
+        let line = msg.to_string();
+        self.send_to_channel(&self.channels[target], &line, Some(addr));
+        self.send_to_links(&link_line(&msg), None);
+        reply_or_ack(echo(&msg, line))
+    }
+
+    /// Gives a message an id and records it in the history, notices and actions aren't recorded.
+    /// A reply must refer to a message the sender can see, otherwise the error reply is returned.
+    fn record(&mut self, addr: SocketAddr, msg: &mut LanChatMessage) -> Result<(), String> {
+        let (target, text, tags) = match &mut msg.command {
+            Command::Msg { text, tags } => (None, text, tags),
+            Command::PrivMsg { target, text, tags } => (Some(target.clone()), text, tags),
+            _ => return Ok(()),
+        };
+        if let Some(parent) = tags.parent {
+            let visible = self
+                .history
+                .get(parent)
+                .is_some_and(|parent| !parent.deleted && self.can_see(addr, parent));
+            if !visible {
+                return Err(no_such_message(parent));
+            }
+        }
+
+        let from = msg
+            .prefix
+            .clone()
+            .unwrap_or_else(|| Prefix::user("unknown"));
+        tags.id = Some(
+            self.history
+                .push(from, addr, target, text.clone(), tags.parent),
+        );
+        Ok(())
+    }
+
+    /// Whether the client at `addr` can see a message from the history. Messages to a channel are
+    /// only visible to its members, and private messages only to the sender and recipient.
+    fn can_see(&self, addr: SocketAddr, message: &StoredMessage) -> bool {
+        match &message.target {
+            None => true,
+            Some(channel) if is_channel_name(channel) => self
+                .channels
+                .get(channel)
+                .is_some_and(|channel| channel.members.contains(&addr)),
+            Some(target) => self
+                .clients
+                .get(&addr)
+                .and_then(Client::nick)
+                .is_some_and(|own| nick::eq(own, target) || nick::eq(own, message.from.name())),
+        }
+    }
+
+    /// The most recent messages visible to the client at `addr` that were sent to everyone, to a
+    /// channel, or between the client and another nick.
+    fn history(&self, addr: SocketAddr, target: Option<&str>) -> Vec<String> {
+        let own = self.clients.get(&addr).and_then(Client::nick);
+        let in_conversation = |message: &StoredMessage| match (target, &message.target) {
+            (None, None) => true,
+            (Some(target), Some(to)) if is_channel_name(target) => target == to,
+            (Some(nick), Some(to)) if !is_channel_name(to) => own.is_some_and(|own| {
+                let from = message.from.name();
+                (nick::eq(from, nick) && nick::eq(to, own))
+                    || (nick::eq(from, own) && nick::eq(to, nick))
+            }),
+            _ => false,
+        };
+
+        let mut messages: Vec<_> = self
+            .history
+            .iter()
+            .rev()
+            .filter(|message| in_conversation(message) && self.can_see(addr, message))
+            .take(HISTORY_REPLAY_LEN)
+            .collect();
+        messages.reverse();
+        messages
+            .into_iter()
+            .map(|message| message.to_message().to_string())
+            .chain(std::iter::once(reply(Command::EndOfHistory)))
+            .collect()
+    }
+
+    /// Applies an EDIT or DELETE to a message in the history and relays it to everyone who can
+    /// see the message. Only the author or an operator can change a message, changes made by an
+    /// operator to another client's message are recorded in the audit log.
+    fn amend(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        let id = match msg.command {
+            Command::Edit { id, .. } | Command::Delete(id) => id,
+            _ => unreachable!("amend called with a command other than EDIT or DELETE"),
+        };
+        let (prefix, is_operator) = match self.clients.get(&addr) {
+            Some(client) => (client.prefix_or_unknown(), client.is_operator),
+            None => return Response::HangUp,
+        };
+
+        let by_author = match self.history.get(id) {
+            Some(message) if !message.deleted && (is_operator || self.can_see(addr, message)) => {
+                message.sent_by == addr
+            }
+            _ => return Response::Reply(vec![no_such_message(id)]),
+        };
+        if !by_author && !is_operator {
+            return Response::Reply(vec![reply(Command::ErrorReply(
+                "You can only change your own messages".to_owned(),
+            ))]);
+        }
+        if !by_author {
+            self.audit.record(prefix.name(), &msg.command.to_string());
+        }
+
+        let message = match self.history.get_mut(id) {
+            Some(message) => message,
+            None => return Response::Reply(vec![no_such_message(id)]),
+        };
+        match &msg.command {
+            Command::Edit { text, .. } => message.text = text.clone(),
+            _ => {
+                message.deleted = true;
+                message.text.clear();
+            }
+        }
+        let message = message.clone();
+
+        msg.prefix = Some(prefix);
+        self.send_to_audience(&message, &msg.to_string());
+        Response::Ack
+    }
+
+    /// Adds or removes a reaction to a message in the history, relaying the change to everyone who
+    /// can see the message.
+    fn react(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        let (id, emoji, add) = match &msg.command {
+            Command::React { id, emoji } => (*id, emoji.as_str(), true),
+            Command::Unreact { id, emoji } => (*id, emoji.as_str(), false),
+            _ => unreachable!("react called with a command other than REACT or UNREACT"),
+        };
+        if !is_valid_reaction(emoji) {
+            return Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "Invalid reaction: {}",
+                emoji
+            )))]);
+        }
+        let visible = self
+            .history
+            .get(id)
+            .is_some_and(|message| !message.deleted && self.can_see(addr, message));
+        let message = match self.history.get_mut(id) {
+            Some(message) if visible => message,
+            _ => return Response::Reply(vec![no_such_message(id)]),
+        };
+        if add && !message.reactions.contains_key(emoji) && message.reactions.len() >= MAX_REACTIONS
+        {
+            return Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "Message {} has too many different reactions",
+                id
+            )))]);
+        }
+
+        let changed = if add {
+            message.react(emoji, addr)
+        } else {
+            message.unreact(emoji, addr)
+        };
+        if changed {
+            let message = message.clone();
+            msg.prefix = self.clients.get(&addr).map(Client::prefix_or_unknown);
+            self.send_to_audience(&message, &msg.to_string());
+        }
+        Response::Ack
+    }
+
+    /// Relays a typing notification to the other members of a channel, a single client, or
+    /// everyone in the main room when there is no target. Only clients that requested the typing
+    /// capability receive it.
+    fn typing(&self, addr: SocketAddr, target: Option<&str>, line: &str) {
+        let recipients: Vec<SocketAddr> = match target {
+            None => self
+                .clients
+                .iter()
+                .filter(|(_, client)| client.prefix.is_some())
+                .map(|(addr, _)| *addr)
+                .collect(),
+            Some(target) if is_channel_name(target) => match self.channels.get(target) {
+                Some(channel) if channel.members.contains(&addr) => {
+                    channel.members.iter().copied().collect()
+                }
+                _ => return,
+            },
+            Some(target) => self.find(target).into_iter().collect(),
+        };
+
+        recipients
+            .iter()
+            .filter(|&&recipient| recipient != addr)
+            .filter_map(|recipient| self.clients.get(recipient))
+            .filter(|client| client.capabilities.contains(CAP_TYPING))
+            .for_each(|client| client.send_line(line.to_owned()));
+    }
+
+    /// Sends a line to everyone who can see a message from the history.
+    fn send_to_audience(&self, message: &StoredMessage, line: &str) {
+        match &message.target {
+            None => {
+                let _ = self.msg_broadcast.send(line.to_owned());
+            }
+            Some(channel) if is_channel_name(channel) => {
+                if let Some(channel) = self.channels.get(channel) {
+                    self.send_to_channel(channel, line, None);
+                }
+            }
+            Some(nick) => {
+                let mut addrs: Vec<_> = [message.from.name(), nick.as_str()]
+                    .into_iter()
+                    .filter_map(|nick| self.find(nick))
+                    .collect();
+                addrs.dedup();
+                for client in addrs.iter().filter_map(|addr| self.clients.get(addr)) {
+                    client.send_line(line.to_owned());
+                }
+            }
+        }
+    }
+
+    /// Relays an offer to send a file to a single client, giving the transfer an id. The offer is
+    /// echoed back to the sender so that it learns the id. Files sent over a direct connection
+    /// don't pass through the server so they aren't limited in size.
+    fn file_offer(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        let (target, size, hash, direct) = match &msg.command {
+            Command::FileOffer {
+                target, size, hash, ..
+            } => (target.clone(), *size, hash.clone(), false),
+            Command::DirectOffer {
+                target, size, hash, ..
+            } => (target.clone(), *size, hash.clone(), true),
+            _ => {
+                unreachable!("file_offer called with a command other than FILEOFFER or DIRECTOFFER")
+            }
+        };
+        msg.prefix = match self.clients.get(&addr) {
+            Some(Client {
+                prefix: Some(prefix),
+                ..
+            }) => Some(prefix.clone()),
+            _ => return Response::Reply(vec![not_registered()]),
+        };
+        let target_addr = match self.find(&target) {
+            Some(target_addr) => target_addr,
+            None => return Response::Reply(vec![no_such_nick(&target)]),
+        };
+
+        let limits = &self.config.limits;
+        if !direct && size > limits.max_file_size {
+            return Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "The file is too large, the limit is {} bytes",
+                limits.max_file_size
+            )))]);
+        }
+        if self.transfers.sending(addr) >= limits.max_transfers {
+            return Response::Reply(vec![reply(Command::ErrorReply(
+                "Too many files are being sent already".to_owned(),
+            ))]);
+        }
+
+        let transfer = self.transfers.offer(addr, target_addr, size, hash, direct);
+        if let Command::FileOffer { id, .. } | Command::DirectOffer { id, .. } = &mut msg.command {
+            *id = Some(transfer);
+        }
+        let line = msg.to_string();
+        self.clients[&target_addr].send_line(line.clone());
+        Response::Reply(vec![line])
+    }
+
+    /// Relays the recipient accepting or rejecting an offered file to the sender, or either side
+    /// cancelling a transfer to the other side. Rejecting or cancelling ends the transfer, as does
+    /// accepting a file sent over a direct connection since the server takes no further part.
+    fn file_reply(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        let id = match msg.command {
+            Command::FileAccept(id)
+            | Command::FileReject(id)
+            | Command::FileCancel(id)
+            | Command::DirectAccept { id, .. } => id,
+            _ => unreachable!("file_reply called with a command that doesn't answer an offer"),
+        };
+        let transfer = match self.transfers.get_mut(id) {
+            Some(transfer) => transfer,
+            None => return Response::Reply(vec![no_such_transfer(id)]),
+        };
+        let other = match msg.command {
+            Command::FileCancel(_) if addr == transfer.from => transfer.to,
+            Command::FileCancel(_) if addr == transfer.to => transfer.from,
+            Command::FileReject(_) if addr == transfer.to && !transfer.accepted => transfer.from,
+            Command::FileAccept(_)
+                if addr == transfer.to && !transfer.accepted && !transfer.direct =>
+            {
+                transfer.from
+            }
+            Command::DirectAccept { addr: listen, .. }
+                if addr == transfer.to && transfer.direct =>
+            {
+                // Otherwise the recipient could have the sender connect to any host, for example
+                // a service on the sender's own network.
+                if listen.ip() != addr.ip() {
+                    return Response::Reply(vec![reply(Command::ErrorReply(
+                        "Files can only be received on the address you're connected from"
+                            .to_owned(),
+                    ))]);
+                }
+                transfer.from
+            }
+            _ => return Response::Reply(vec![no_such_transfer(id)]),
+        };
+        if let Command::FileAccept(_) = msg.command {
+            transfer.accepted = true;
+        } else {
+            self.transfers.remove(id);
+        }
+
+        msg.prefix = self.clients.get(&addr).map(Client::prefix_or_unknown);
+        if let Some(client) = self.clients.get(&other) {
+            client.send_line(msg.to_string());
+        }
+        Response::Ack
+    }
+
+    /// Checks a chunk of a file, or the hash sent once the file is complete, and has the sender's
+    /// connection relay it to the recipient.
+    fn file_data(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        let id = match msg.command {
+            Command::FileChunk { id, .. } | Command::FileDone { id, .. } => id,
+            _ => unreachable!("file_data called with a command other than FILECHUNK or FILEDONE"),
+        };
+        let transfer = match self.transfers.get_mut(id) {
+            Some(transfer) if transfer.from == addr => transfer,
+            _ => return Response::Reply(vec![no_such_transfer(id)]),
+        };
+        let checked = match &msg.command {
+            Command::FileChunk { offset, data, .. } => match file::decode_chunk(data) {
+                Ok(data) if !data.is_empty() && data.len() <= file::CHUNK_SIZE => {
+                    transfer.relay(*offset, data.len() as u64)
+                }
+                _ => Err("Invalid file chunk".to_owned()),
+            },
+            Command::FileDone { .. } if !transfer.is_complete() => Err(format!(
+                "Only {} bytes of the file have been sent",
+                transfer.relayed
+            )),
+            Command::FileDone { hash, .. } if *hash != transfer.hash => {
+                Err("The hash doesn't match the offered file".to_owned())
+            }
+            _ => Ok(()),
+        };
+        if let Err(error) = checked {
+            return Response::Reply(vec![reply(Command::ErrorReply(error))]);
+        }
+
+        let to = transfer.to;
+        if let Command::FileDone { .. } = msg.command {
+            self.transfers.remove(id);
+        }
+        msg.prefix = self.clients.get(&addr).map(Client::prefix_or_unknown);
+        match self.clients.get(&to) {
+            Some(client) => Response::Relay {
+                to: client.files.clone(),
+                line: msg.to_string(),
+                transfer: id,
+            },
+            None => Response::Ack,
+        }
+    }
+
+    /// The messages in a thread that are visible to the client at `addr`.
+    fn thread(&self, addr: SocketAddr, id: u64) -> Vec<String> {
+        let thread: Vec<_> = self
+            .history
+            .thread(id)
+            .into_iter()
+            .filter(|message| self.can_see(addr, message))
+            .collect();
+        if thread.is_empty() {
+            return vec![no_such_message(id)];
+        }
+        thread
+            .into_iter()
+            .map(|message| message.to_message().to_string())
+            .chain(std::iter::once(reply(Command::EndOfThread(id))))
+            .collect()
+    }
+
+    fn join(&mut self, addr: SocketAddr, name: String) -> Response {
+        if !is_channel_name(&name) {
+            return Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "Invalid channel name: {}",
+                name
+            )))]);
+        }
+        let prefix = match self.clients.get(&addr).and_then(|c| c.prefix.clone()) {
+            Some(prefix) => prefix,
+            None => return Response::Reply(vec![not_registered()]),
+        };
+
+        let channel = self.channels.entry(name.clone()).or_default();
+        if !channel.members.insert(addr) {
+            return Response::Ack;
+        }
+
+        let joined = LanChatMessage {
+            prefix: Some(prefix),
+            command: Command::Join(name.clone()),
+        };
+        // The joining client is sent the join before the topic, rather than having the join race
+        // the reply.
+        self.send_to_channel(&self.channels[&name], &joined.to_string(), Some(addr));
+
+        Response::Reply(vec![joined.to_string(), self.topic_reply(&name)])
+    }
+
+    fn part(&mut self, addr: SocketAddr, name: &str) -> Response {
+        let channel = match self.channels.get(name) {
+            Some(channel) => channel,
+            None => return Response::Reply(vec![no_such_channel(name)]),
+        };
+        if !channel.members.contains(&addr) {
+            return Response::Reply(vec![not_on_channel(name)]);
+        }
+
+        let parted = LanChatMessage {
+            prefix: self.clients.get(&addr).map(Client::prefix_or_unknown),
+            command: Command::Part(name.to_owned()),
+        };
+        self.send_to_channel(channel, &parted.to_string(), None);
+
+        if let Some(channel) = self.channels.get_mut(name) {
+            channel.members.remove(&addr);
+            if channel.is_abandoned() {
+                self.channels.remove(name);
+            }
+        }
+        Response::Ack
+    }
+
+    /// Replies with the topic of a channel when `text` is `None`, otherwise sets the topic and
+    /// notifies the members of the channel.
+    fn topic(&mut self, addr: SocketAddr, name: &str, text: Option<String>) -> Response {
+        let text = match text {
+            Some(text) => text,
+            None if self.channels.contains_key(name) || self.topics.get(name).is_some() => {
+                return Response::Reply(vec![self.topic_reply(name)])
+            }
+            None => return Response::Reply(vec![no_such_channel(name)]),
+        };
+
+        let prefix = match self.clients.get(&addr).and_then(|c| c.prefix.clone()) {
+            Some(prefix) => prefix,
+            None => return Response::Reply(vec![not_registered()]),
+        };
+        match self.channels.get(name) {
+            Some(channel) if channel.members.contains(&addr) => {}
+            Some(_) => return Response::Reply(vec![not_on_channel(name)]),
+            None => return Response::Reply(vec![no_such_channel(name)]),
+        }
+
+        let topic = if text.is_empty() {
+            None
+        } else {
+            Some(Topic {
+                text: text.clone(),
+                set_by: prefix.name().to_owned(),
+                set_at: unix_secs(SystemTime::now()),
+            })
+        };
+        let saved = self.topics.set(name, topic);
+
+        let changed = LanChatMessage {
+            prefix: Some(prefix),
+            command: Command::Topic {
+                channel: name.to_owned(),
+                text: Some(text),
+            },
+        };
+        self.send_to_channel(&self.channels[name], &changed.to_string(), None);
+        match saved {
+            Ok(()) => Response::Ack,
+            Err(e) => Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "Topic set but could not be saved: {}",
+                e
+            )))]),
+        }
+    }
+
+    fn topic_reply(&self, name: &str) -> String {
+        match self.topics.get(name) {
+            Some(topic) => reply(Command::TopicReply {
+                channel: name.to_owned(),
+                nick: topic.set_by.clone(),
+                set_at: topic.set_at,
+                text: topic.text.clone(),
+            }),
+            None => reply(Command::NoTopic(name.to_owned())),
+        }
+    }
+
+    /// Sends a message to every member of a channel, optionally skipping one member such as the
+    /// sender of the message.
+    fn send_to_channel(&self, channel: &Channel, msg: &str, except: Option<SocketAddr>) {
+        for member in channel.members.iter().filter(|&&m| Some(m) != except) {
+            if let Some(client) = self.clients.get(member) {
+                client.send_line(msg.to_owned());
+            }
+        }
+    }
+
+    /// Grants operator privileges to a client if the credentials match those in the config.
+    fn oper(&mut self, addr: SocketAddr, name: &str, password: &str) -> Response {
+        let valid = self
+            .config
+            .operators
+            .iter()
+            .any(|operator| operator.name == name && operator.password == password);
+        if !valid {
+            return Response::Reply(vec![reply(Command::ErrorReply(
+                "Invalid operator credentials".to_owned(),
+            ))]);
+        }
+
+        match self.clients.get_mut(&addr) {
+            Some(client) if client.prefix.is_some() => {
+                client.is_operator = true;
+                let nick = client.prefix_or_unknown().name().to_owned();
+                self.audit.record(&nick, &format!("OPER {}", name));
+                Response::Reply(vec![reply(Command::YoureOper)])
+            }
+            _ => Response::Reply(vec![not_registered()]),
+        }
+    }
+
+    /// Handles the moderation commands that can only be issued by operators. Every action is
+    /// broadcast to all clients and recorded in the audit log.
+    fn moderate(&mut self, addr: SocketAddr, msg: LanChatMessage) -> Response {
+        match self.operator(addr) {
+            Ok(prefix) => self.apply_moderation(prefix, msg),
+            Err(response) => response,
+        }
+    }
+
+    /// Applies a moderation command on behalf of `prefix`, who has already been checked to be an
+    /// operator.
+    fn apply_moderation(&mut self, prefix: Prefix, mut msg: LanChatMessage) -> Response {
+        let nick = match &msg.command {
+            Command::Kick { nick, .. }
+            | Command::Kill { nick, .. }
+            | Command::Mute(nick)
+            | Command::Unmute(nick) => nick.clone(),
+            _ => unreachable!("moderate called with a non-moderation command"),
+        };
+        let target = match self.find(&nick) {
+            Some(target) => target,
+            None => return Response::Reply(vec![no_such_nick(&nick)]),
+        };
+
+        if let Command::Kick { channel, .. } = &msg.command {
+            match self.channels.get(channel) {
+                Some(c) if c.members.contains(&target) => {}
+                Some(_) => {
+                    return Response::Reply(vec![reply(Command::ErrorReply(format!(
+                        "{} is not on channel: {}",
+                        nick, channel
+                    )))])
+                }
+                None => return Response::Reply(vec![no_such_channel(channel)]),
+            }
+        }
+
+        self.audit.record(prefix.name(), &msg.command.to_string());
+        msg.prefix = Some(prefix);
+        let _ = self.msg_broadcast.send(msg.to_string());
+
+        match &msg.command {
+            Command::Kick { channel, .. } => {
+                if let Some(c) = self.channels.get_mut(channel) {
+                    c.members.remove(&target);
+                    if c.is_abandoned() {
+                        self.channels.remove(channel);
+                    }
+                }
+            }
+            Command::Kill { .. } => {
+                if let Some(client) = self.clients.get_mut(&target) {
+                    client.hang_up(Response::HangUp);
+                }
+                self.disconnect(target);
+            }
+            Command::Mute(_) | Command::Unmute(_) => {
+                if let Some(client) = self.clients.get_mut(&target) {
+                    client.muted = matches!(msg.command, Command::Mute(_));
+                }
+            }
+            _ => {}
+        }
+        Response::Ack
+    }
+
+    /// Bans a mask and disconnects any connected clients that match it.
+    fn ban(
+        &mut self,
+        addr: SocketAddr,
+        mask: &str,
+        duration: Option<u64>,
+        reason: Option<String>,
+    ) -> Response {
+        let prefix = match self.operator(addr) {
+            Ok(prefix) => prefix,
+            Err(response) => return response,
+        };
+        let mask: BanMask = match mask.parse() {
+            Ok(mask) => mask,
+            Err(e) => return Response::Reply(vec![reply(Command::ErrorReply(e))]),
+        };
+
+        let ban = Ban::new(
+            mask,
+            prefix.name().to_owned(),
+            reason.unwrap_or_else(|| "No reason given".to_owned()),
+            duration.map(Duration::from_secs),
+        );
+        self.audit
+            .record(prefix.name(), &format!("BAN {}", ban.mask));
+        let saved = self.bans.add(ban);
+        self.disconnect_banned();
+        match saved {
+            Ok(()) => Response::Ack,
+            Err(e) => Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "Ban applied but could not be saved: {}",
+                e
+            )))]),
+        }
+    }
+
+    /// Hangs up on every connected client whose address or nick matches a ban.
+    fn disconnect_banned(&mut self) {
+        let banned_clients: Vec<(SocketAddr, String)> = self
+            .clients
+            .iter()
+            .filter_map(|(client_addr, client)| {
+                self.bans
+                    .find_ip(client_addr.ip())
+                    .or_else(|| client.nick().and_then(|nick| self.bans.find_nick(nick)))
+                    .map(|ban| (*client_addr, banned(ban)))
+            })
+            .collect();
+        for (banned_addr, reason) in banned_clients {
+            if let Some(client) = self.clients.get_mut(&banned_addr) {
+                client.hang_up(Response::Refuse(reason));
+            }
+            self.disconnect(banned_addr);
+        }
+    }
+
+    fn unban(&mut self, addr: SocketAddr, mask: &str) -> Response {
+        let prefix = match self.operator(addr) {
+            Ok(prefix) => prefix,
+            Err(response) => return response,
+        };
+        let mask: BanMask = match mask.parse() {
+            Ok(mask) => mask,
+            Err(e) => return Response::Reply(vec![reply(Command::ErrorReply(e))]),
+        };
+
+        match self.bans.remove(&mask) {
+            Ok(true) => {
+                self.audit.record(prefix.name(), &format!("UNBAN {}", mask));
+                Response::Ack
+            }
+            Ok(false) => Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "No such ban: {}",
+                mask
+            )))]),
+            Err(e) => Response::Reply(vec![reply(Command::ErrorReply(format!(
+                "Ban removed but could not be saved: {}",
+                e
+            )))]),
+        }
+    }
+
+    fn ban_list(&self, addr: SocketAddr) -> Response {
+        if let Err(response) = self.operator(addr) {
+            return response;
+        }
+
+        let replies = self
+            .bans
+            .iter()
+            .map(|ban| {
+                reply(Command::BanReply {
+                    mask: ban.mask.to_string(),
+                    set_by: ban.set_by.clone(),
+                    expires: ban.expires.unwrap_or(0),
+                    reason: ban.reason.clone(),
+                })
+            })
+            .chain(std::iter::once(reply(Command::EndOfBanList)))
+            .collect();
+        Response::Reply(replies)
+    }
+
+    /// Returns the prefix of the client if it is an operator, otherwise returns the response to
+    /// send to the client.
+    fn operator(&self, addr: SocketAddr) -> Result<Prefix, Response> {
+        match self.clients.get(&addr) {
+            Some(client) if client.is_operator => Ok(client.prefix_or_unknown()),
+            _ => Err(Response::Reply(vec![reply(Command::ErrorReply(
+                "Permission denied: you're not an operator".to_owned(),
+            ))])),
+        }
+    }
+
+    /// Describes each connected client, one per line, for the admin socket.
+    fn list_connections(&self) -> Vec<String> {
+        let mut clients: Vec<_> = self.clients.iter().collect();
+        clients.sort_by_key(|(addr, _)| **addr);
+        clients
+            .into_iter()
+            .map(|(addr, client)| {
+                format!(
+                    "{} {} idle={} away={} operator={} muted={}",
+                    addr,
+                    client.nick().unwrap_or("*"),
+                    client.idle().as_secs(),
+                    client.away.is_some(),
+                    client.is_operator,
+                    client.muted,
+                )
+            })
+            .collect()
+    }
+
+    /// Disconnects the client with the given nick on behalf of the admin socket.
+    fn admin_kick(&mut self, nick: String, reason: Option<String>) -> AdminResponse {
+        if self.find(&nick).is_none() {
+            return Err(format!("No such nick: {}", nick));
+        }
+        let msg = LanChatMessage {
+            prefix: None,
+            command: Command::Kill { nick, reason },
+        };
+        self.apply_moderation(self.server_prefix(), msg);
+        Ok(Vec::new())
+    }
+
+    /// The prefix used for messages that originate from the server itself rather than a client.
+    fn server_prefix(&self) -> Prefix {
+        Prefix::Server(self.config.server_name.clone())
+    }
+
+    /// The replies containing the message of the day.
+    fn motd(&self) -> Vec<String> {
+        let motd = |command| {
+            LanChatMessage {
+                prefix: Some(self.server_prefix()),
+                command,
+            }
+            .to_string()
+        };
+        if self.motd.is_empty() {
+            return vec![motd(Command::NoMotd)];
+        }
+        self.motd
+            .iter()
+            .map(|line| motd(Command::MotdReply(line.clone())))
+            .chain(std::iter::once(motd(Command::EndOfMotd)))
+            .collect()
+    }
+
+    /// Broadcasts a notice from the server to all clients.
+    fn notice(&self, text: String) {
+        let msg = LanChatMessage {
+            prefix: Some(self.server_prefix()),
+            command: Command::Notice { target: None, text },
+        };
+        let _ = self.msg_broadcast.send(msg.to_string());
+    }
+
+    /// Applies a reloaded config. Returns lines describing the outcome, including the settings
+    /// that changed but only take effect after a restart.
+    fn reload(
+        &mut self,
+        config: Config,
+        audit: AuditLog,
+        bans: Option<BanList>,
+        motd: Vec<String>,
+    ) -> Vec<String> {
+        let restart_required = self.config.restart_required(&config);
+        // Other servers and clients already know this server by its current name.
+        let config = Config {
+            server_name: self.config.server_name.clone(),
+            ..config
+        };
+        self.history.set_capacity(config.history_len);
+        self.config = config;
+        self.audit = audit;
+        self.motd = motd;
+        if let Some(bans) = bans {
+            self.bans = bans;
+            self.disconnect_banned();
+        }
+        info!(?restart_required, "reloaded config");
+
+        let mut lines = vec!["Reloaded config".to_owned()];
+        if !restart_required.is_empty() {
+            lines.push(format!(
+                "Restart required to apply: {}",
+                restart_required.join(", ")
+            ));
+        }
+        lines
+    }
+
+    fn stats(&self) -> Vec<String> {
+        let registered = self.clients.values().filter(|c| c.prefix.is_some()).count();
+        let operators = self.clients.values().filter(|c| c.is_operator).count();
+        vec![
+            format!("clients {}", self.clients.len()),
+            format!("registered {}", registered),
+            format!("operators {}", operators),
+            format!("channels {}", self.channels.len()),
+            format!("bans {}", self.bans.iter().count()),
+            format!("links {}", self.links.authenticated(None).count()),
+            format!("remote_users {}", self.links.users().count()),
+        ]
+    }
+
+    /// Describes the server to clients looking for servers on the local network.
+    fn announce(&self) -> Command {
+        Command::Announce {
+            name: self.config.server_name.clone(),
+            port: self.config.listen.port(),
+            tls: false,
+            users: self.clients.values().filter(|c| c.prefix.is_some()).count(),
+        }
+    }
+
+    /// Records a connection made to another server for a configured link and sends this server's
+    /// name and the password of the link, the link is authenticated once the other server does
+    /// the same. The link is refused if the other server is already part of the network.
+    fn link(&mut self, addr: SocketAddr, name: String, send: mpsc::Sender<String>) -> Response {
+        let password = match self.link_password(&name) {
+            Some(password) => password.to_owned(),
+            None => return Response::Refuse(format!("No link is configured for {}", name)),
+        };
+        if self.links.is_linked(&name) {
+            return Response::Refuse(format!("Already linked to {}", name));
+        }
+
+        let link = Link {
+            name,
+            send,
+            authenticated: false,
+        };
+        link.send_line(self.server_line(&password));
+        self.links.insert(addr, link);
+        Response::Ack
+    }
+
+    /// Turns a client connection into a link when another server authenticates with SERVER,
+    /// replying with this server's name and the users on this side of the link.
+    fn accept_link(&mut self, addr: SocketAddr, name: String, password: String) -> Response {
+        if self
+            .clients
+            .get(&addr)
+            .is_some_and(|client| client.prefix.is_some())
+        {
+            return Response::Reply(vec![reply(Command::ErrorReply(
+                "Only servers can send SERVER".to_owned(),
+            ))]);
+        }
+        if let Err(reason) = self.check_link(&name, &password) {
+            self.disconnect(addr);
+            return Response::Refuse(reason);
+        }
+        if self.clients.remove(&addr).is_none() {
+            return Response::HangUp;
+        }
+
+        info!(%addr, %name, "linked to server");
+        let mut burst = vec![self.server_line(&password)];
+        burst.extend(self.burst(addr));
+        // There is always room for the burst.
+        let (send, lines) = mpsc::channel(LINK_QUEUE + burst.len());
+        let link = Link {
+            name,
+            send,
+            authenticated: true,
+        };
+        for line in burst {
+            link.send_line(line);
+        }
+        self.links.insert(addr, link);
+        Response::Linked(lines)
+    }
+
+    /// Checks the name and password sent with SERVER by another server.
+    fn check_link(&self, name: &str, password: &str) -> Result<(), String> {
+        if self.link_password(name) != Some(password) {
+            return Err("Invalid link credentials".to_owned());
+        }
+        if self.links.is_linked(name) {
+            return Err(format!("Already linked to {}", name));
+        }
+        Ok(())
+    }
+
+    /// The password configured for the link to the server with the given name.
+    fn link_password(&self, name: &str) -> Option<&str> {
+        self.config
+            .links
+            .iter()
+            .find(|link| link.name == name)
+            .map(|link| link.password.as_str())
+    }
+
+    /// The SERVER command this server authenticates with.
+    fn server_line(&self, password: &str) -> String {
+        reply(Command::Server {
+            name: self.config.server_name.clone(),
+            password: password.to_owned(),
+        })
+    }
+
+    /// Introduces the users known on this side of a new link to the server at `addr`.
+    fn burst(&self, addr: SocketAddr) -> Vec<String> {
+        let local = self.registered().into_iter().map(|(_, client, nick)| {
+            (
+                self.config.server_name.as_str(),
+                nick,
+                client.connected_secs(),
+                &client.away,
+            )
+        });
+        let remote = self
+            .links
+            .users()
+            .filter(|user| user.link != addr)
+            .map(|user| {
+                (
+                    user.server.as_str(),
+                    user.nick.as_str(),
+                    user.registered,
+                    &user.away,
+                )
+            });
+
+        let mut lines = Vec::new();
+        for (server, nick, registered, away) in local.chain(remote) {
+            let introduce = LanChatMessage {
+                prefix: Some(Prefix::Server(server.to_owned())),
+                command: Command::Introduce {
+                    nick: nick.to_owned(),
+                    registered,
+                },
+            };
+            lines.push(introduce.to_string());
+            if away.is_some() {
+                let away = LanChatMessage {
+                    prefix: Some(Prefix::user(nick)),
+                    command: Command::Away(away.clone()),
+                };
+                lines.push(away.to_string());
+            }
+        }
+        lines
+    }
+
+    /// Handles a message sent by another server over a link. Until the other server has
+    /// authenticated with SERVER nothing else is accepted, after that it can introduce users and
+    /// relay what they do.
+    fn link_message(&mut self, addr: SocketAddr, msg: LanChatMessage) -> Response {
+        let link = match self.links.get(addr) {
+            Some(link) => link,
+            None => return Response::HangUp,
+        };
+        if !link.authenticated {
+            let checked = match &msg.command {
+                Command::Server { name, password } if *name == link.name => {
+                    self.check_link(name, password)
+                }
+                _ => Err("Expected SERVER".to_owned()),
+            };
+            return match checked {
+                Ok(()) => {
+                    info!(%addr, name = %link.name, "linked to server");
+                    if let Some(link) = self.links.get_mut(addr) {
+                        link.authenticated = true;
+                    }
+                    Response::Reply(self.burst(addr))
+                }
+                Err(reason) => {
+                    warn!(%addr, %reason, "refused link");
+                    Response::HangUp
+                }
+            };
+        }
+
+        match (&msg.prefix, &msg.command) {
+            (Some(Prefix::Server(server)), Command::Introduce { nick, registered }) => {
+                self.introduce(addr, server.clone(), nick.clone(), *registered)
+            }
+            // A server can only speak for the users known through its own link.
+            (Some(Prefix::User { nick, .. }), _)
+                if self
+                    .links
+                    .find_user(nick)
+                    .is_some_and(|user| user.link == addr) =>
+            {
+                self.remote_user_message(addr, msg)
+            }
+            _ => Response::Ack,
+        }
+    }
+
+    /// Adds a user introduced by another server and passes the introduction on to the other
+    /// links. If the nick is already in use, whichever user loses the collision is renamed.
+    fn introduce(
+        &mut self,
+        addr: SocketAddr,
+        server: String,
+        nick: String,
+        registered: u64,
+    ) -> Response {
+        // A user on this server, or on a server known through another link, has come back around
+        // so the links form a loop.
+        let looped = server == self.config.server_name
+            || self
+                .links
+                .users()
+                .any(|u| u.server == server && u.link != addr)
+            || self
+                .links
+                .authenticated(Some(addr))
+                .any(|(_, l)| l.name == server);
+        if looped {
+            warn!(%addr, %server, "links form a loop");
+            return Response::HangUp;
+        }
+
+        let introduce = LanChatMessage {
+            prefix: Some(Prefix::Server(server.clone())),
+            command: Command::Introduce {
+                nick: nick.clone(),
+                registered,
+            },
+        };
+        self.send_to_links(&introduce.to_string(), Some(addr));
+
+        let nick = self.resolve_collision(nick, registered, &server);
+        self.links.add_user(RemoteUser {
+            nick,
+            server,
+            link: addr,
+            registered,
+            away: None,
+        });
+        Response::Ack
+    }
+
+    /// Resolves a collision between a user on another server taking `nick` and any user that
+    /// already has it, given when the user taking the nick registered and the server it is
+    /// connected to. Returns the nick the user ends up with, renaming the user that already has
+    /// it if that user loses.
+    fn resolve_collision(&mut self, nick: String, registered: u64, server: &str) -> String {
+        let existing = match self.find(&nick) {
+            Some(client_addr) => Some((
+                self.clients[&client_addr].connected_secs(),
+                self.config.server_name.clone(),
+            )),
+            None => self
+                .links
+                .find_user(&nick)
+                .map(|user| (user.registered, user.server.clone())),
+        };
+        match existing {
+            Some((existing_registered, existing_server))
+                if keeps_nick(
+                    (existing_registered, &existing_server),
+                    (registered, server),
+                ) =>
+            {
+                collision_nick(&nick, server)
+            }
+            Some((_, existing_server)) => {
+                self.rename(&nick, &existing_server);
+                nick
+            }
+            None => nick,
+        }
+    }
+
+    /// Renames the user that has lost a nick collision with a user on another server, telling
+    /// the user if it is connected to this server, or the clients of this server if it isn't.
+    fn rename(&mut self, nick: &str, server: &str) {
+        let renamed = collision_nick(nick, server);
+        if let Some(client_addr) = self.find(nick) {
+            if let Some(client) = self.clients.get_mut(&client_addr) {
+                info!(addr = %client_addr, %nick, %renamed, "renamed after nick collision");
+                let changed = LanChatMessage {
+                    prefix: client.prefix.replace(Prefix::user(renamed.clone())),
+                    command: Command::Nick(renamed),
+                };
+                client.send_line(changed.to_string());
             }
+        } else if let Some(user) = self.links.find_user_mut(nick) {
+            let changed = LanChatMessage {
+                prefix: Some(Prefix::user(user.nick.clone())),
+                command: Command::Nick(renamed.clone()),
+            };
+            user.nick = renamed;
+            let _ = self.msg_broadcast.send(changed.to_string());
+        }
+    }
+
+    /// Applies a change made by a user on another server, or delivers a message the user has
+    /// sent, and passes it on to the other links.
+    fn remote_user_message(&mut self, addr: SocketAddr, mut msg: LanChatMessage) -> Response {
+        let relayed = link_line(&msg);
+        let from = msg
+            .prefix
+            .as_ref()
+            .map(|prefix| prefix.name().to_owned())
+            .unwrap_or_default();
+
+        match &msg.command {
             Command::Nick(nick) => {
-                prefixes.insert(addr, Prefix { nick });
+                let (registered, server) = match self.links.find_user(&from) {
+                    Some(user) => (user.registered, user.server.clone()),
+                    None => return Response::Ack,
+                };
+                // The nick may have been taken on this server or another one while the change
+                // was on its way, which is resolved like a collision when the user was introduced.
+                // A change to the case of the user's own nick can't collide.
+                let nick = if nick::eq(nick, &from) {
+                    nick.clone()
+                } else {
+                    self.resolve_collision(nick.clone(), registered, &server)
+                };
+                if let Some(user) = self.links.find_user_mut(&from) {
+                    user.nick = nick.clone();
+                }
+                // Clients of this server see the nick the user ended up with.
+                let changed = LanChatMessage {
+                    prefix: msg.prefix.clone(),
+                    command: Command::Nick(nick),
+                };
+                let _ = self.msg_broadcast.send(changed.to_string());
             }
             Command::Quit => {
-                prefixes.remove(&addr);
-                let _ = respond.send(Response::HangUp);
+                if self.links.remove_user(&from).is_some() {
+                    let _ = self.msg_broadcast.send(msg.to_string());
+                }
+            }
+            Command::Away(away) => {
+                if let Some(user) = self.links.find_user_mut(&from) {
+                    user.away = away.clone();
+                }
+                let _ = self.msg_broadcast.send(msg.to_string());
+            }
+            Command::Msg { .. }
+            | Command::Notice { target: None, .. }
+            | Command::Action { target: None, .. } => {
+                let _ = self.record(addr, &mut msg);
+                let _ = self.msg_broadcast.send(msg.to_string());
+            }
+            Command::PrivMsg { target, .. }
+            | Command::Notice {
+                target: Some(target),
+                ..
+            }
+            | Command::Action {
+                target: Some(target),
+                ..
+            } => {
+                let target = target.clone();
+                if is_channel_name(&target) {
+                    if self.channels.contains_key(&target) {
+                        let _ = self.record(addr, &mut msg);
+                        self.send_to_channel(&self.channels[&target], &msg.to_string(), None);
+                    }
+                } else {
+                    // Private messages only go towards the recipient.
+                    if let Some(target_addr) = self.find(&target) {
+                        let _ = self.record(addr, &mut msg);
+                        self.clients[&target_addr].send_line(msg.to_string());
+                    } else if let Some(user) = self.links.find_user(&target) {
+                        if user.link != addr {
+                            self.send_to_link(user.link, relayed);
+                        }
+                    }
+                    return Response::Ack;
+                }
+            }
+            _ => return Response::Ack,
+        }
+        self.send_to_links(&relayed, Some(addr));
+        Response::Ack
+    }
+
+    /// Removes a lost link and the users known through it, telling the servers still linked that
+    /// the users have left and the clients of this server about the split.
+    fn netsplit(&mut self, addr: SocketAddr) {
+        let (link, lost) = match self.links.remove(addr) {
+            Some(removed) => removed,
+            None => return,
+        };
+        if !link.authenticated {
+            return;
+        }
+        warn!(%addr, name = %link.name, users = lost.len(), "lost link");
+        for user in &lost {
+            let quit = LanChatMessage {
+                prefix: Some(Prefix::user(user.nick.clone())),
+                command: Command::Quit,
+            };
+            self.send_to_links(&quit.to_string(), None);
+        }
+        self.notice(format!(
+            "Netsplit: lost the link to {}, {} users left",
+            link.name,
+            lost.len()
+        ));
+    }
+
+    /// Sends a line to every server linked to this one, other than the one at `except`.
+    fn send_to_links(&mut self, line: &str, except: Option<SocketAddr>) {
+        let lagging: Vec<SocketAddr> = self
+            .links
+            .authenticated(except)
+            .filter(|(_, link)| !link.send_line(line.to_owned()))
+            .map(|(addr, _)| addr)
+            .collect();
+        for addr in lagging {
+            self.lagging_link(addr);
+        }
+    }
+
+    /// Sends a line to the server linked at `addr`.
+    fn send_to_link(&mut self, addr: SocketAddr, line: String) {
+        if self
+            .links
+            .get(addr)
+            .is_some_and(|link| !link.send_line(line))
+        {
+            self.lagging_link(addr);
+        }
+    }
+
+    /// Drops a link to a server that isn't keeping up with the lines sent to it, as if the link
+    /// had been lost. The servers are brought back in agreement when the link is made again.
+    fn lagging_link(&mut self, addr: SocketAddr) {
+        warn!(%addr, "link is not keeping up");
+        self.netsplit(addr);
+    }
+
+    /// Returns the address of the client registered with the given nick, ignoring case.
+    fn find(&self, nick: &str) -> Option<SocketAddr> {
+        self.clients
+            .iter()
+            .find(|(_, client)| client.nick().is_some_and(|n| nick::eq(n, nick)))
+            .map(|(addr, _)| *addr)
+    }
+
+    fn nick(&mut self, addr: SocketAddr, nick: String) -> Response {
+        if let Err(error) = self.check_nick(addr, &nick) {
+            return Response::Reply(vec![reply(Command::ErrorReply(error))]);
+        }
+        if let Some(ban) = self.bans.find_nick(&nick) {
+            let reason = banned(ban);
+            self.disconnect(addr);
+            return Response::Refuse(reason);
+        }
+        let client = match self.clients.get_mut(&addr) {
+            Some(client) => client,
+            None => return Response::HangUp,
+        };
+        info!(%addr, %nick, "registered nick");
+        let old = client.prefix.replace(Prefix::user(nick.clone()));
+        let registered = client.connected_secs();
+
+        // The rest of the network learns of the new nick.
+        let changed = match &old {
+            Some(prefix) => LanChatMessage {
+                prefix: Some(prefix.clone()),
+                command: Command::Nick(nick),
+            },
+            None => LanChatMessage {
+                prefix: Some(self.server_prefix()),
+                command: Command::Introduce { nick, registered },
+            },
+        };
+        self.send_to_links(&changed.to_string(), None);
+
+        // The MOTD is only sent the first time a client sets its nick.
+        if old.is_some() {
+            Response::Ack
+        } else {
+            Response::Reply(self.motd())
+        }
+    }
+
+    /// Checks that the client at `addr` can register `nick`.
+    fn check_nick(&self, addr: SocketAddr, nick: &str) -> Result<(), String> {
+        if !nick::is_valid(nick) || (!self.config.unicode_nicks && !nick.is_ascii()) {
+            return Err(format!("Invalid nickname: {}", nick));
+        }
+        let in_use = match self.find(nick) {
+            Some(other) => other != addr,
+            None => self.links.find_user(nick).is_some(),
+        };
+        if in_use {
+            return Err(format!("Nickname is already in use: {}", nick));
+        }
+        Ok(())
+    }
+
+    /// Returns the registered clients sorted by nick.
+    fn registered(&self) -> Vec<(&SocketAddr, &Client, &str)> {
+        let mut registered: Vec<_> = self
+            .clients
+            .iter()
+            .filter_map(|(addr, client)| client.nick().map(|nick| (addr, client, nick)))
+            .collect();
+        registered.sort_by(|a, b| a.2.cmp(b.2));
+        registered
+    }
+
+    fn names(&self, channel: Option<&str>) -> Vec<String> {
+        let members = channel.map(|name| self.channels.get(name).map(|c| &c.members));
+
+        let mut names: Vec<(&str, bool)> = self
+            .registered()
+            .into_iter()
+            .filter(|(addr, _, _)| match members {
+                Some(Some(members)) => members.contains(addr),
+                Some(None) => false,
+                None => true,
+            })
+            .map(|(_, client, nick)| (nick, client.away.is_some()))
+            .collect();
+        // Channel membership isn't shared between servers, so users on other servers are only
+        // listed when names are requested for the whole network.
+        if channel.is_none() {
+            names.extend(
+                self.links
+                    .users()
+                    .map(|user| (user.nick.as_str(), user.away.is_some())),
+            );
+            names.sort();
+        }
+
+        names
+            .into_iter()
+            .map(|(nick, away)| {
+                reply(Command::NamesReply {
+                    nick: nick.to_owned(),
+                    away,
+                })
+            })
+            .chain(std::iter::once(reply(Command::EndOfNames)))
+            .collect()
+    }
+
+    fn who(&self) -> Vec<String> {
+        self.registered()
+            .into_iter()
+            .map(|(addr, client, nick)| {
+                reply(Command::WhoReply {
+                    nick: nick.to_owned(),
+                    addr: *addr,
+                    idle: client.idle().as_secs(),
+                    away: client.away.is_some(),
+                })
+            })
+            .chain(std::iter::once(reply(Command::EndOfWho)))
+            .collect()
+    }
+
+    fn whois(&self, requester: SocketAddr, nick: &str) -> Vec<String> {
+        let (addr, client) = match self
+            .clients
+            .iter()
+            .find(|(_, client)| client.nick().is_some_and(|n| nick::eq(n, nick)))
+        {
+            Some(found) => found,
+            None => {
+                return match self.links.find_user(nick) {
+                    Some(user) => remote_whois(user),
+                    None => vec![no_such_nick(nick)],
+                }
+            }
+        };
+        let is_operator = self
+            .clients
+            .get(&requester)
+            .is_some_and(|client| client.is_operator);
+
+        let mut replies = vec![reply(Command::WhoisUser {
+            nick: nick.to_owned(),
+            connected: client.connected_secs(),
+            idle: client.idle().as_secs(),
+        })];
+
+        let mut channels: Vec<String> = self
+            .channels
+            .iter()
+            .filter(|(_, channel)| channel.members.contains(addr))
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !channels.is_empty() {
+            channels.sort();
+            replies.push(reply(Command::WhoisChannels {
+                nick: nick.to_owned(),
+                channels,
+            }));
+        }
+
+        if let Some(away) = &client.away {
+            replies.push(reply(Command::AwayReply {
+                nick: nick.to_owned(),
+                msg: away.to_owned(),
+            }));
+        }
+
+        if is_operator {
+            replies.push(reply(Command::WhoisAddr {
+                nick: nick.to_owned(),
+                addr: *addr,
+            }));
+        }
+        replies.push(reply(Command::EndOfWhois(nick.to_owned())));
+        replies
+    }
+}
+
+/// The WHOIS replies for a user on another server, which only knows when the user connected and
+/// whether they are away.
+fn remote_whois(user: &RemoteUser) -> Vec<String> {
+    let mut replies = vec![reply(Command::WhoisUser {
+        nick: user.nick.clone(),
+        connected: user.registered,
+        idle: 0,
+    })];
+    if let Some(away) = &user.away {
+        replies.push(reply(Command::AwayReply {
+            nick: user.nick.clone(),
+            msg: away.clone(),
+        }));
+    }
+    replies.push(reply(Command::EndOfWhois(user.nick.clone())));
+    replies
+}
+
+/// Formats a message to relay over a link to another server, without the tags given to it by
+/// this server, see the [`link`](crate::link) module.
+fn link_line(msg: &LanChatMessage) -> String {
+    let mut msg = msg.clone();
+    if let Command::Msg { tags, .. } | Command::PrivMsg { tags, .. } = &mut msg.command {
+        *tags = MsgTags::default();
+    }
+    msg.to_string()
+}
+
+/// Formats a reply sent from the server to a client.
+fn reply(command: Command) -> String {
+    LanChatMessage {
+        prefix: None,
+        command,
+    }
+    .to_string()
+}
+
+fn no_such_nick(nick: &str) -> String {
+    reply(Command::ErrorReply(format!("No such nick: {}", nick)))
+}
+
+fn no_such_transfer(id: u64) -> String {
+    reply(Command::ErrorReply(format!(
+        "No such file transfer: {}",
+        id
+    )))
+}
+
+fn no_such_message(id: u64) -> String {
+    reply(Command::ErrorReply(format!("No such message: {}", id)))
+}
+
+/// Messages sent to a nick or channel are echoed back to the sender, so that the sender learns the
+/// id the message was given.
+fn echo(msg: &LanChatMessage, line: String) -> Vec<String> {
+    match msg.command {
+        Command::PrivMsg { .. } => vec![line],
+        _ => Vec::new(),
+    }
+}
+
+fn reply_or_ack(replies: Vec<String>) -> Response {
+    if replies.is_empty() {
+        Response::Ack
+    } else {
+        Response::Reply(replies)
+    }
+}
+
+fn no_such_channel(channel: &str) -> String {
+    reply(Command::ErrorReply(format!("No such channel: {}", channel)))
+}
+
+fn not_on_channel(channel: &str) -> String {
+    reply(Command::ErrorReply(format!(
+        "You're not on that channel: {}",
+        channel
+    )))
+}
+
+fn banned(ban: &Ban) -> String {
+    format!("You are banned from this server: {}", ban.reason)
+}
+
+fn not_registered() -> String {
+    reply(Command::ErrorReply(
+        "You must set a nick with NICK first".to_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::command::MsgTags;
+
+    use crate::{
+        config::{LinkConfig, OperatorConfig},
+        limits::Limits,
+    };
+
+    /// A server whose broadcasts aren't received, subscribe to `msg_broadcast` to receive them.
+    fn test_server(config: Config) -> Server {
+        let (b_send, _) = broadcast::channel(8);
+        Server::new(b_send, config, AuditLog::default(), BanList::default())
+    }
+
+    fn message(command: Command) -> LanChatMessage {
+        LanChatMessage {
+            prefix: None,
+            command,
+        }
+    }
+
+    fn connect(server: &mut Server, addr: &str) -> (SocketAddr, mpsc::Receiver<Response>) {
+        let (addr, recv, _) = connect_with_hang_up(server, addr);
+        (addr, recv)
+    }
+
+    /// Connects a client, also returning the receiver used to hang up the connection.
+    fn connect_with_hang_up(
+        server: &mut Server,
+        addr: &str,
+    ) -> (
+        SocketAddr,
+        mpsc::Receiver<Response>,
+        oneshot::Receiver<Response>,
+    ) {
+        let addr = addr.parse().unwrap();
+        let (send, recv) = mpsc::channel(8);
+        let (files, _) = mpsc::channel(8);
+        let (hang_up, hang_up_recv) = oneshot::channel();
+        assert!(matches!(
+            server.connect(addr, send, files, hang_up),
+            Response::Ack
+        ));
+        (addr, recv, hang_up_recv)
+    }
+
+    /// Returns the lines sent directly to a client.
+    fn lines(recv: &mut mpsc::Receiver<Response>) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(response) = recv.try_recv() {
+            lines.extend(replies(response));
+        }
+        lines
+    }
+
+    /// Returns the lines of a reply, panicking if the response is anything else.
+    fn replies(response: Response) -> Vec<String> {
+        match response {
+            Response::Reply(replies) => replies,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn names_lists_registered_clients() {
+        let mut server = test_server(Config::default());
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (anon, _) = connect(&mut server, "127.0.0.1:5001");
+        let (bob, _) = connect(&mut server, "127.0.0.1:5002");
+
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        server.handle_message(bob, message(Command::Away(Some("lunch".to_owned()))));
+
+        let expected = vec![
+            "NAMREPLY bob G\r\n".to_owned(),
+            "NAMREPLY olly H\r\n".to_owned(),
+            "ENDOFNAMES\r\n".to_owned(),
+        ];
+        assert_eq!(
+            expected,
+            replies(server.handle_message(anon, message(Command::Names(None))))
+        );
+    }
+
+    #[test]
+    fn motd_is_sent_on_registration() {
+        let mut server = test_server(Config::default());
+        server.motd = vec!["Welcome!".to_owned(), "Be nice.".to_owned()];
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+
+        let expected = vec![
+            ":lanchat.local MOTDREPLY :Welcome!\r\n".to_owned(),
+            ":lanchat.local MOTDREPLY :Be nice.\r\n".to_owned(),
+            ":lanchat.local ENDOFMOTD\r\n".to_owned(),
+        ];
+        assert_eq!(
+            expected,
+            replies(server.handle_message(olly, message(Command::Nick("olly".to_owned()))))
+        );
+        // Changing nick doesn't resend the MOTD, but it can be requested.
+        assert!(matches!(
+            server.handle_message(olly, message(Command::Nick("oliver".to_owned()))),
+            Response::Ack
+        ));
+        assert_eq!(
+            expected,
+            replies(server.handle_message(olly, message(Command::Motd)))
+        );
+    }
+
+    #[test]
+    fn nick_collisions_ignore_case() {
+        let mut server = test_server(Config::default());
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (other, _) = connect(&mut server, "127.0.0.1:5001");
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+
+        let expected = vec!["ERROR :Nickname is already in use: OLLY\r\n".to_owned()];
+        assert_eq!(
+            expected,
+            replies(server.handle_message(other, message(Command::Nick("OLLY".to_owned()))))
+        );
+        // Unicode nicks are refused unless enabled in the config.
+        let expected = vec!["ERROR :Invalid nickname: zoë\r\n".to_owned()];
+        assert_eq!(
+            expected,
+            replies(server.handle_message(other, message(Command::Nick("zoë".to_owned()))))
+        );
+        // A client can change the case of its own nick.
+        assert!(matches!(
+            server.handle_message(olly, message(Command::Nick("Olly".to_owned()))),
+            Response::Ack
+        ));
+    }
+
+    #[test]
+    fn private_message_to_away_user_replies_with_away_message() {
+        let mut server = test_server(Config::default());
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        server.handle_message(bob, message(Command::Away(Some("lunch".to_owned()))));
+
+        let msg = message(Command::PrivMsg {
+            target: "bob".to_owned(),
+            text: "hi".to_owned(),
+            tags: MsgTags::default(),
+        });
+        let expected = vec![":olly MSG bob id=1 :hi\r\n", "AWAYREPLY bob :lunch\r\n"];
+        assert_eq!(expected, replies(server.handle_message(olly, msg)));
+        assert_eq!(vec![":olly MSG bob id=1 :hi\r\n"], lines(&mut bob_recv));
+
+        // Notices don't trigger the away reply.
+        let notice = message(Command::Notice {
+            target: Some("bob".to_owned()),
+            text: "build finished".to_owned(),
+        });
+        assert!(matches!(server.handle_message(olly, notice), Response::Ack));
+        assert_eq!(
+            vec![":olly NOTICE bob :build finished\r\n"],
+            lines(&mut bob_recv)
+        );
+    }
+
+    #[test]
+    fn replies_must_refer_to_visible_messages() {
+        let mut server = test_server(Config::default());
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+        let (eve, _) = connect(&mut server, "127.0.0.1:5002");
+        for (addr, nick) in [(olly, "olly"), (bob, "bob"), (eve, "eve")] {
+            server.handle_message(addr, message(Command::Nick(nick.to_owned())));
+        }
+        server.handle_message(olly, message(Command::Join("#general".to_owned())));
+        server.handle_message(bob, message(Command::Join("#general".to_owned())));
+
+        let msg = |target: &str, text: &str, parent| {
+            message(Command::PrivMsg {
+                target: target.to_owned(),
+                text: text.to_owned(),
+                tags: MsgTags {
+                    parent,
+                    ..MsgTags::default()
+                },
+            })
+        };
+        server.handle_message(olly, msg("#general", "lunch?", None));
+        lines(&mut bob_recv);
+        assert_eq!(
+            vec![":bob MSG #general id=2 parent=1 :yes\r\n"],
+            replies(server.handle_message(bob, msg("#general", "yes", Some(1))))
+        );
+
+        // Eve isn't in #general so can't reply to or fetch its messages.
+        assert_eq!(
+            vec!["ERROR :No such message: 1\r\n"],
+            replies(server.handle_message(eve, msg("bob", "me too", Some(1))))
+        );
+        assert_eq!(
+            vec!["ERROR :No such message: 2\r\n"],
+            replies(server.handle_message(eve, message(Command::Thread(2))))
+        );
+
+        let expected = vec![
+            ":olly MSG #general id=1 :lunch?\r\n",
+            ":bob MSG #general id=2 parent=1 :yes\r\n",
+            "ENDOFTHREAD 2\r\n",
+        ];
+        assert_eq!(
+            expected,
+            replies(server.handle_message(olly, message(Command::Thread(2))))
+        );
+    }
+
+    #[test]
+    fn topic_is_broadcast_and_sent_on_join() {
+        let mut server = test_server(Config::default());
+        let (olly, mut olly_recv) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        assert_eq!(
+            vec![":olly JOIN #general\r\n", "NOTOPIC #general\r\n"],
+            replies(server.handle_message(olly, message(Command::Join("#general".to_owned()))))
+        );
+
+        let set_topic = message(Command::Topic {
+            channel: "#general".to_owned(),
+            text: Some("pizza".to_owned()),
+        });
+        server.handle_message(olly, set_topic);
+        assert_eq!(
+            vec![":olly TOPIC #general :pizza\r\n"],
+            lines(&mut olly_recv)
+        );
+
+        let joined =
+            replies(server.handle_message(bob, message(Command::Join("#general".to_owned()))));
+        assert_eq!(":bob JOIN #general\r\n", joined[0]);
+        assert!(joined[1].starts_with("TOPICREPLY #general olly "));
+        assert!(joined[1].ends_with(" :pizza\r\n"));
+        assert!(lines(&mut bob_recv).is_empty());
+        assert_eq!(vec![":bob JOIN #general\r\n"], lines(&mut olly_recv));
+    }
+
+    #[test]
+    fn topic_outlives_channel_and_restart() {
+        let path =
+            std::env::temp_dir().join(format!("lanchat-server-topics-{}.toml", std::process::id()));
+        let mut server = test_server(Config::default());
+        server.topics = TopicList::load(Some(&path)).unwrap();
+        let (olly, _olly_recv) = connect(&mut server, "127.0.0.1:5000");
+
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(olly, message(Command::Join("#general".to_owned())));
+        let set_topic = message(Command::Topic {
+            channel: "#general".to_owned(),
+            text: Some("pizza".to_owned()),
+        });
+        assert!(matches!(
+            server.handle_message(olly, set_topic),
+            Response::Ack
+        ));
+        server.handle_message(olly, message(Command::Part("#general".to_owned())));
+        assert!(!server.channels.contains_key("#general"));
+
+        let mut server = test_server(Config::default());
+        server.topics = TopicList::load(Some(&path)).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let (bob, _bob_recv) = connect(&mut server, "127.0.0.1:5001");
+
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        let joined =
+            replies(server.handle_message(bob, message(Command::Join("#general".to_owned()))));
+        assert!(joined[1].starts_with("TOPICREPLY #general olly "));
+        assert!(joined[1].ends_with(" :pizza\r\n"));
+    }
+
+    #[test]
+    fn only_operators_can_kill() {
+        let config = Config {
+            operators: vec![OperatorConfig {
+                name: "admin".to_owned(),
+                password: "hunter2".to_owned(),
+            }],
+            ..Config::default()
+        };
+        let mut server = test_server(config);
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, _bob_recv, mut bob_hang_up) = connect_with_hang_up(&mut server, "127.0.0.1:5001");
+
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+
+        let kill = Command::Kill {
+            nick: "bob".to_owned(),
+            reason: None,
+        };
+        assert!(matches!(
+            server.handle_message(olly, message(kill.clone())),
+            Response::Reply(_)
+        ));
+        assert!(server.clients.contains_key(&bob));
+
+        let oper = Command::Oper {
+            name: "admin".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        server.handle_message(olly, message(oper));
+        assert!(matches!(
+            server.handle_message(olly, message(kill)),
+            Response::Ack
+        ));
+        assert!(matches!(bob_hang_up.try_recv(), Ok(Response::HangUp)));
+        assert!(!server.clients.contains_key(&bob));
+    }
+
+    #[test]
+    fn kill_hangs_up_a_client_that_is_not_keeping_up() {
+        let config = Config {
+            operators: vec![OperatorConfig {
+                name: "admin".to_owned(),
+                password: "hunter2".to_owned(),
+            }],
+            ..Config::default()
+        };
+        let mut server = test_server(config);
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, _bob_recv, mut bob_hang_up) = connect_with_hang_up(&mut server, "127.0.0.1:5001");
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        let oper = Command::Oper {
+            name: "admin".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        server.handle_message(olly, message(oper));
+
+        // Fill the queue of lines sent to bob.
+        while server.clients[&bob].send.capacity() > 0 {
+            let msg = Command::PrivMsg {
+                target: "bob".to_owned(),
+                text: "hello".to_owned(),
+                tags: MsgTags::default(),
+            };
+            server.handle_message(olly, message(msg));
+        }
+        let kill = Command::Kill {
+            nick: "bob".to_owned(),
+            reason: None,
+        };
+        server.handle_message(olly, message(kill));
+        assert!(matches!(bob_hang_up.try_recv(), Ok(Response::HangUp)));
+        assert!(!server.clients.contains_key(&bob));
+    }
+
+    #[test]
+    fn only_the_author_or_an_operator_can_change_a_message() {
+        let config = Config {
+            operators: vec![OperatorConfig {
+                name: "admin".to_owned(),
+                password: "hunter2".to_owned(),
+            }],
+            ..Config::default()
+        };
+        let mut server = test_server(config);
+        let (olly, mut olly_recv) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+        for (addr, nick) in [(olly, "olly"), (bob, "bob")] {
+            server.handle_message(addr, message(Command::Nick(nick.to_owned())));
+            server.handle_message(addr, message(Command::Join("#general".to_owned())));
+        }
+        let msg = Command::PrivMsg {
+            target: "#general".to_owned(),
+            text: "teh plan".to_owned(),
+            tags: MsgTags::default(),
+        };
+        server.handle_message(olly, message(msg));
+        lines(&mut olly_recv);
+        lines(&mut bob_recv);
+
+        let edit = |text: &str| {
+            message(Command::Edit {
+                id: 1,
+                text: text.to_owned(),
+            })
+        };
+        assert!(matches!(
+            server.handle_message(bob, edit("bob was here")),
+            Response::Reply(_)
+        ));
+        assert!(matches!(
+            server.handle_message(olly, edit("the plan")),
+            Response::Ack
+        ));
+        // The edit is relayed to everyone in the channel, including the author.
+        assert_eq!(vec![":olly EDIT 1 :the plan\r\n"], lines(&mut olly_recv));
+        assert_eq!(vec![":olly EDIT 1 :the plan\r\n"], lines(&mut bob_recv));
+        let expected = vec![":olly MSG #general id=1 :the plan\r\n", "ENDOFHISTORY\r\n"];
+        assert_eq!(
+            expected,
+            replies(
+                server.handle_message(bob, message(Command::History(Some("#general".to_owned()))))
+            )
+        );
+
+        let oper = Command::Oper {
+            name: "admin".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        server.handle_message(bob, message(oper));
+        assert!(matches!(
+            server.handle_message(bob, message(Command::Delete(1))),
+            Response::Ack
+        ));
+        assert_eq!(vec![":bob DELETE 1\r\n"], lines(&mut olly_recv));
+        assert_eq!(
+            vec!["ENDOFHISTORY\r\n"],
+            replies(
+                server.handle_message(olly, message(Command::History(Some("#general".to_owned()))))
+            )
+        );
+    }
+
+    #[test]
+    fn reactions_are_relayed_and_replayed() {
+        let mut server = test_server(Config::default());
+        let mut b_recv = server.msg_broadcast.subscribe();
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, _) = connect(&mut server, "127.0.0.1:5001");
+        for (addr, nick) in [(olly, "olly"), (bob, "bob")] {
+            server.handle_message(addr, message(Command::Nick(nick.to_owned())));
+        }
+        let msg = Command::Msg {
+            text: "shipped".to_owned(),
+            tags: MsgTags::default(),
+        };
+        server.handle_message(olly, message(msg));
+        let react = |emoji: &str| {
+            message(Command::React {
+                id: 1,
+                emoji: emoji.to_owned(),
+            })
+        };
+        server.handle_message(bob, react("🎉"));
+        server.handle_message(olly, react("🎉"));
+        assert!(matches!(
+            server.handle_message(bob, react("not valid")),
+            Response::Reply(_)
+        ));
+
+        let relayed: Vec<_> = std::iter::from_fn(|| b_recv.try_recv().ok()).collect();
+        assert_eq!(
+            vec![
+                ":olly MSG id=1 :shipped\r\n",
+                ":bob REACT 1 🎉\r\n",
+                ":olly REACT 1 🎉\r\n",
+            ],
+            relayed
+        );
+        let expected = vec![
+            ":olly MSG id=1 reactions=🎉:2 :shipped\r\n",
+            "ENDOFHISTORY\r\n",
+        ];
+        assert_eq!(
+            expected,
+            replies(server.handle_message(bob, message(Command::History(None))))
+        );
+    }
+
+    #[test]
+    fn typing_is_only_sent_to_clients_with_the_capability() {
+        let mut server = test_server(Config::default());
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+        let (eve, mut eve_recv) = connect(&mut server, "127.0.0.1:5002");
+        for (addr, nick) in [(olly, "olly"), (bob, "bob"), (eve, "eve")] {
+            server.handle_message(addr, message(Command::Nick(nick.to_owned())));
+            server.handle_message(addr, message(Command::Join("#general".to_owned())));
+        }
+        lines(&mut bob_recv);
+        lines(&mut eve_recv);
+
+        let cap = Command::Cap(vec!["typing".to_owned(), "telepathy".to_owned()]);
+        assert_eq!(
+            vec!["CAP :typing\r\n"],
+            replies(server.handle_message(bob, message(cap)))
+        );
+
+        let typing = Command::Typing {
+            target: Some("#general".to_owned()),
+            typing: true,
+        };
+        for _ in 0..10 {
+            server.handle_message(olly, message(typing.clone()));
+        }
+        // Only a burst of notifications is relayed, and only to bob.
+        let expected = vec![":olly TYPING #general start\r\n"; 5];
+        assert_eq!(expected, lines(&mut bob_recv));
+        assert!(lines(&mut eve_recv).is_empty());
+        assert_eq!(0, server.history.iter().count());
+    }
+
+    #[test]
+    fn typing_without_a_target_is_sent_to_the_main_room() {
+        let mut server = test_server(Config::default());
+        let mut b_recv = server.msg_broadcast.subscribe();
+        let (olly, mut olly_recv) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+        let (eve, mut eve_recv) = connect(&mut server, "127.0.0.1:5002");
+        for (addr, nick) in [(olly, "olly"), (bob, "bob"), (eve, "eve")] {
+            server.handle_message(addr, message(Command::Nick(nick.to_owned())));
+        }
+        for addr in [olly, bob] {
+            server.handle_message(addr, message(Command::Cap(vec!["typing".to_owned()])));
+        }
+        lines(&mut olly_recv);
+        lines(&mut bob_recv);
+        lines(&mut eve_recv);
+        while b_recv.try_recv().is_ok() {}
+
+        let typing = Command::Typing {
+            target: None,
+            typing: true,
+        };
+        assert!(matches!(
+            server.handle_message(olly, message(typing)),
+            Response::Ack
+        ));
+        assert_eq!(vec![":olly TYPING start\r\n"], lines(&mut bob_recv));
+        assert!(lines(&mut olly_recv).is_empty());
+        assert!(lines(&mut eve_recv).is_empty());
+        assert!(b_recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn files_are_relayed_once_accepted() {
+        let config = Config {
+            limits: Limits {
+                max_file_size: 4,
+                max_transfers: 1,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let mut server = test_server(config);
+        let (olly, mut olly_recv) = connect(&mut server, "127.0.0.1:5000");
+        let bob = "127.0.0.1:5001".parse().unwrap();
+        let (send, mut bob_recv) = mpsc::channel(8);
+        let (files, _files_recv) = mpsc::channel(8);
+        server.connect(bob, send, files, oneshot::channel().0);
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        lines(&mut olly_recv);
+        lines(&mut bob_recv);
+
+        // sha256("test")
+        let hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let offer = |size| Command::FileOffer {
+            target: "bob".to_owned(),
+            id: None,
+            size,
+            hash: hash.to_owned(),
+            name: "test.txt".to_owned(),
+        };
+        let relayed_offer = format!(":olly FILEOFFER bob 1 4 {} :test.txt\r\n", hash);
+        assert!(replies(server.handle_message(olly, message(offer(5))))[0].contains("too large"));
+        assert_eq!(
+            vec![relayed_offer.clone()],
+            replies(server.handle_message(olly, message(offer(4))))
+        );
+        assert_eq!(vec![relayed_offer], lines(&mut bob_recv));
+        assert!(replies(server.handle_message(olly, message(offer(4))))[0].contains("Too many"));
+
+        let chunk = |offset, data: &[u8]| Command::FileChunk {
+            id: 1,
+            offset,
+            data: file::encode_chunk(data),
+        };
+        // Chunks aren't relayed until the file is accepted.
+        assert!(matches!(
+            server.handle_message(olly, message(chunk(0, b"te"))),
+            Response::Reply(_)
+        ));
+        server.handle_message(bob, message(Command::FileAccept(1)));
+        assert_eq!(vec![":bob FILEACCEPT 1\r\n"], lines(&mut olly_recv));
+
+        match server.handle_message(olly, message(chunk(0, b"te"))) {
+            Response::Relay { line, transfer, .. } => {
+                assert_eq!(":olly FILECHUNK 1 0 :dGU=\r\n", line);
+                assert_eq!(1, transfer);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let done = Command::FileDone {
+            id: 1,
+            hash: hash.to_owned(),
+        };
+        // The file isn't complete until every byte has been sent.
+        assert!(matches!(
+            server.handle_message(olly, message(done.clone())),
+            Response::Reply(_)
+        ));
+        assert!(matches!(
+            server.handle_message(olly, message(chunk(2, b"st"))),
+            Response::Relay { .. }
+        ));
+        assert!(matches!(
+            server.handle_message(olly, message(done)),
+            Response::Relay { .. }
+        ));
+        assert!(matches!(
+            server.handle_message(bob, message(Command::FileCancel(1))),
+            Response::Reply(_)
+        ));
+    }
+
+    #[test]
+    fn only_the_direct_transfer_handshake_is_relayed() {
+        let mut server = test_server(Config::default());
+        let (olly, mut olly_recv) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.2:5001");
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        lines(&mut olly_recv);
+
+        // Direct transfers aren't subject to the size limit for files sent through the server.
+        let hash = "0".repeat(64);
+        let offer = Command::DirectOffer {
+            target: "bob".to_owned(),
+            id: None,
+            size: 1 << 40,
+            hash: hash.clone(),
+            name: "backup.tar".to_owned(),
+        };
+        server.handle_message(olly, message(offer));
+        assert_eq!(
+            vec![format!(
+                ":olly DIRECTOFFER bob 1 1099511627776 {} :backup.tar\r\n",
+                hash
+            )],
+            lines(&mut bob_recv)
+        );
+
+        // The file must be accepted with the address to connect to, which must belong to the
+        // recipient.
+        assert!(matches!(
+            server.handle_message(bob, message(Command::FileAccept(1))),
+            Response::Reply(_)
+        ));
+        let accept = |addr: &str| Command::DirectAccept {
+            id: 1,
+            addr: addr.parse().unwrap(),
+            token: "9f86d081884c7d65".to_owned(),
+        };
+        assert!(matches!(
+            server.handle_message(bob, message(accept("127.0.0.1:40000"))),
+            Response::Reply(_)
+        ));
+        assert!(matches!(
+            server.handle_message(bob, message(accept("127.0.0.2:40000"))),
+            Response::Ack
+        ));
+        assert_eq!(
+            vec![":bob DIRECTACCEPT 1 127.0.0.2:40000 9f86d081884c7d65\r\n"],
+            lines(&mut olly_recv)
+        );
+
+        // The server takes no further part in the transfer.
+        let chunk = Command::FileChunk {
+            id: 1,
+            offset: 0,
+            data: file::encode_chunk(b"data"),
+        };
+        assert!(matches!(
+            server.handle_message(olly, message(chunk)),
+            Response::Reply(_)
+        ));
+        assert_eq!(0, server.transfers.sending(olly));
+    }
+
+    #[test]
+    fn file_transfers_are_cancelled_on_disconnect() {
+        let mut server = test_server(Config::default());
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, mut bob_recv) = connect(&mut server, "127.0.0.1:5001");
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        let offer = Command::FileOffer {
+            target: "bob".to_owned(),
+            id: None,
+            size: 1024,
+            hash: "0".repeat(64),
+            name: "build.log".to_owned(),
+        };
+        server.handle_message(olly, message(offer));
+        lines(&mut bob_recv);
+
+        server.disconnect(olly);
+        assert_eq!(vec![":olly FILECANCEL 1\r\n"], lines(&mut bob_recv));
+        assert_eq!(0, server.transfers.sending(olly));
+    }
+
+    #[test]
+    fn ban_hangs_up_a_client_that_is_not_keeping_up() {
+        let config = Config {
+            operators: vec![OperatorConfig {
+                name: "admin".to_owned(),
+                password: "hunter2".to_owned(),
+            }],
+            ..Config::default()
+        };
+        let mut server = test_server(config);
+        let (olly, _) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, _bob_recv, mut bob_hang_up) = connect_with_hang_up(&mut server, "127.0.0.2:5001");
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+        let oper = Command::Oper {
+            name: "admin".to_owned(),
+            password: "hunter2".to_owned(),
+        };
+        server.handle_message(olly, message(oper));
+
+        // Fill the queue of lines sent to bob.
+        while server.clients[&bob].send.capacity() > 0 {
+            let msg = Command::PrivMsg {
+                target: "bob".to_owned(),
+                text: "hello".to_owned(),
+                tags: MsgTags::default(),
+            };
+            server.handle_message(olly, message(msg));
+        }
+        let ban = Command::Ban {
+            mask: "bob".to_owned(),
+            duration: None,
+            reason: Some("Flooding".to_owned()),
+        };
+        server.handle_message(olly, message(ban));
+        match bob_hang_up.try_recv() {
+            Ok(Response::Refuse(reason)) => assert!(reason.contains("Flooding")),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(!server.clients.contains_key(&bob));
+    }
+
+    #[test]
+    fn banned_nick_is_refused() {
+        let mut server = test_server(Config::default());
+        server
+            .bans
+            .add(Ban::new(
+                "bob*".parse().unwrap(),
+                "olly".to_owned(),
+                "Flooding".to_owned(),
+                None,
+            ))
+            .unwrap();
+        let (bob, _) = connect(&mut server, "127.0.0.1:5000");
+
+        assert!(matches!(
+            server.handle_message(bob, message(Command::Nick("bobby".to_owned()))),
+            Response::Refuse(_)
+        ));
+        assert!(!server.clients.contains_key(&bob));
+    }
+
+    #[test]
+    fn reload_applies_bans_and_keeps_server_name() {
+        let mut server = test_server(Config::default());
+        let (olly, _olly_recv) = connect(&mut server, "127.0.0.1:5000");
+        let (bob, _bob_recv, mut bob_hang_up) = connect_with_hang_up(&mut server, "127.0.0.2:5001");
+        server.handle_message(olly, message(Command::Nick("olly".to_owned())));
+        server.handle_message(bob, message(Command::Nick("bob".to_owned())));
+
+        let mut bans = BanList::default();
+        bans.add(Ban::new(
+            "bob*".parse().unwrap(),
+            "olly".to_owned(),
+            "Flooding".to_owned(),
+            None,
+        ))
+        .unwrap();
+        let config = Config {
+            server_name: "floor1.example.lan".to_owned(),
+            ..Config::default()
+        };
+        let lines = server.reload(config, AuditLog::default(), Some(bans), Vec::new());
+
+        assert_eq!(
+            vec!["Reloaded config", "Restart required to apply: server_name"],
+            lines
+        );
+        assert_eq!(Config::default().server_name, server.config.server_name);
+        match bob_hang_up.try_recv() {
+            Ok(Response::Refuse(reason)) => assert!(reason.contains("Flooding")),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(!server.clients.contains_key(&bob));
+        assert!(server.clients.contains_key(&olly));
+    }
+
+    #[test]
+    fn connections_per_ip_are_limited() {
+        let config = Config {
+            limits: Limits {
+                max_per_ip: 1,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let mut server = test_server(config);
+        connect(&mut server, "127.0.0.1:5000");
+
+        let (send, _recv) = mpsc::channel(8);
+        let (files, _files_recv) = mpsc::channel(8);
+        assert!(matches!(
+            server.connect(
+                "127.0.0.1:5001".parse().unwrap(),
+                send.clone(),
+                files.clone(),
+                oneshot::channel().0
+            ),
+            Response::Refuse(_)
+        ));
+        assert!(matches!(
+            server.connect(
+                "127.0.0.2:5000".parse().unwrap(),
+                send,
+                files,
+                oneshot::channel().0
+            ),
+            Response::Ack
+        ));
+    }
+
+    /// Servers with the given names, each configured to link with all of the others, and the
+    /// receivers of their broadcasts.
+    fn network(names: &[&str]) -> (Vec<Server>, Vec<broadcast::Receiver<String>>) {
+        names
+            .iter()
+            .map(|name| {
+                let links = names
+                    .iter()
+                    .filter(|peer| *peer != name)
+                    .map(|peer| LinkConfig {
+                        name: peer.to_string(),
+                        password: "secret".to_owned(),
+                        connect: None,
+                    })
+                    .collect();
+                let config = Config {
+                    server_name: name.to_string(),
+                    links,
+                    ..Config::default()
+                };
+                let server = test_server(config);
+                let b_recv = server.msg_broadcast.subscribe();
+                (server, b_recv)
+            })
+            .unzip()
+    }
+
+    /// A link between two servers of a test network.
+    struct TestLink {
+        ends: [LinkEnd; 2],
+    }
+
+    /// One end of a test link, the index of a server, the address that server knows the link by,
+    /// and the lines it sends over the link. Until the server has made the connection a link, it
+    /// only sends replies.
+    struct LinkEnd {
+        server: usize,
+        addr: SocketAddr,
+        replies: Vec<String>,
+        lines: Option<mpsc::Receiver<String>>,
+    }
+
+    impl LinkEnd {
+        /// The lines sent since the last call.
+        fn sent(&mut self) -> Vec<String> {
+            let mut sent = std::mem::take(&mut self.replies);
+            if let Some(lines) = &mut self.lines {
+                while let Ok(line) = lines.try_recv() {
+                    sent.push(line);
+                }
+            }
+            sent
+        }
+    }
+
+    /// Links server `a` to server `b` as if `a` had connected to `b`.
+    fn link(servers: &mut [Server], a: usize, a_addr: &str, b: usize, b_addr: &str) -> TestLink {
+        let (a_addr, b_addr) = (a_addr.parse().unwrap(), b_addr.parse().unwrap());
+        let (a_send, a_lines) = mpsc::channel(LINK_QUEUE);
+        let name = servers[b].config.server_name.clone();
+        assert!(matches!(
+            servers[a].link(b_addr, name, a_send),
+            Response::Ack
+        ));
+        let (b_send, _) = mpsc::channel(32);
+        let (files, _) = mpsc::channel(8);
+        assert!(matches!(
+            servers[b].connect(a_addr, b_send, files, oneshot::channel().0),
+            Response::Ack
+        ));
+        TestLink {
+            ends: [
+                LinkEnd {
+                    server: a,
+                    addr: b_addr,
+                    replies: Vec::new(),
+                    lines: Some(a_lines),
+                },
+                LinkEnd {
+                    server: b,
+                    addr: a_addr,
+                    replies: Vec::new(),
+                    lines: None,
+                },
+            ],
+        }
+    }
+
+    /// Passes the lines sent over each link to the server at the other end, until no server has
+    /// anything more to send.
+    fn settle(servers: &mut [Server], links: &mut [TestLink]) {
+        loop {
+            let mut quiet = true;
+            for link in links.iter_mut() {
+                for from in 0..2 {
+                    let sent = link.ends[from].sent();
+                    quiet &= sent.is_empty();
+                    let to = &mut link.ends[1 - from];
+                    for line in sent {
+                        match servers[to.server].handle_message(to.addr, line.parse().unwrap()) {
+                            // Replies are sent back over the link.
+                            Response::Reply(replies) => to.replies.extend(replies),
+                            Response::Linked(lines) => to.lines = Some(lines),
+                            Response::HangUp | Response::Refuse(_) => {
+                                servers[to.server].disconnect(to.addr)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            if quiet {
+                return;
+            }
+        }
+    }
+
+    fn names(server: &mut Server, addr: SocketAddr) -> Vec<String> {
+        replies(server.handle_message(addr, message(Command::Names(None))))
+    }
+
+    #[test]
+    fn linked_servers_form_one_network() {
+        let (mut servers, mut broadcasts) = network(&["floor1.lan", "floor2.lan"]);
+        let (olly, _) = connect(&mut servers[0], "10.0.1.2:5000");
+        let (bob, mut bob_recv) = connect(&mut servers[1], "10.0.2.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        servers[1].handle_message(bob, message(Command::Nick("bob".to_owned())));
+
+        let mut links = [link(&mut servers, 0, "10.0.1.1:40000", 1, "10.0.2.1:3000")];
+        settle(&mut servers, &mut links);
+
+        // Users registered after the link is made are introduced too.
+        let (carol, _) = connect(&mut servers[1], "10.0.2.3:5000");
+        servers[1].handle_message(carol, message(Command::Nick("carol".to_owned())));
+        servers[1].handle_message(carol, message(Command::Away(Some("lunch".to_owned()))));
+        settle(&mut servers, &mut links);
+
+        let expected = vec![
+            "NAMREPLY bob H\r\n".to_owned(),
+            "NAMREPLY carol G\r\n".to_owned(),
+            "NAMREPLY olly H\r\n".to_owned(),
+            "ENDOFNAMES\r\n".to_owned(),
+        ];
+        assert_eq!(expected, names(&mut servers[0], olly));
+        assert_eq!(expected, names(&mut servers[1], bob));
+
+        // Private messages are routed to the recipient's server, which gives them its own id.
+        let msg = message(Command::PrivMsg {
+            target: "bob".to_owned(),
+            text: "hi".to_owned(),
+            tags: MsgTags::default(),
+        });
+        servers[0].handle_message(olly, msg);
+        settle(&mut servers, &mut links);
+        assert_eq!(vec![":olly MSG bob id=1 :hi\r\n"], lines(&mut bob_recv));
+
+        broadcasts[1].try_recv().unwrap();
+        let msg = message(Command::Msg {
+            text: "hello".to_owned(),
+            tags: MsgTags::default(),
+        });
+        servers[0].handle_message(olly, msg);
+        settle(&mut servers, &mut links);
+        assert_eq!(
+            ":olly MSG id=2 :hello\r\n",
+            broadcasts[1].try_recv().unwrap()
+        );
+
+        // Nicks are unique across the network.
+        let (other, _) = connect(&mut servers[1], "10.0.2.4:5000");
+        let expected = vec!["ERROR :Nickname is already in use: olly\r\n".to_owned()];
+        assert_eq!(
+            expected,
+            replies(servers[1].handle_message(other, message(Command::Nick("olly".to_owned()))))
+        );
+    }
+
+    #[test]
+    fn only_messages_are_relayed_without_their_ids() {
+        let (mut servers, mut broadcasts) = network(&["floor1.lan", "floor2.lan"]);
+        let (olly, _) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        servers[0].handle_message(olly, message(Command::Cap(vec!["typing".to_owned()])));
+        let (bob, mut bob_recv) = connect(&mut servers[1], "10.0.2.2:5000");
+        servers[1].handle_message(bob, message(Command::Nick("bob".to_owned())));
+        servers[1].handle_message(bob, message(Command::Cap(vec!["typing".to_owned()])));
+        let mut links = [link(&mut servers, 0, "10.0.1.1:40000", 1, "10.0.2.1:3000")];
+        settle(&mut servers, &mut links);
+        lines(&mut bob_recv);
+
+        let msg = |parent| {
+            message(Command::Msg {
+                text: "lunch?".to_owned(),
+                tags: MsgTags {
+                    parent,
+                    ..MsgTags::default()
+                },
+            })
+        };
+        while broadcasts[1].try_recv().is_ok() {}
+        servers[0].handle_message(olly, msg(None));
+        servers[0].handle_message(olly, msg(Some(1)));
+        settle(&mut servers, &mut links);
+        // A reply reaches floor2.lan as a message of its own, with floor2.lan's id.
+        assert_eq!(
+            ":olly MSG id=1 :lunch?\r\n",
+            broadcasts[1].try_recv().unwrap()
+        );
+        assert_eq!(
+            ":olly MSG id=2 :lunch?\r\n",
+            broadcasts[1].try_recv().unwrap()
+        );
+
+        // Commands that refer to a message by id, and typing notifications, aren't relayed.
+        let commands = [
+            Command::Edit {
+                id: 1,
+                text: "dinner?".to_owned(),
+            },
+            Command::React {
+                id: 1,
+                emoji: "👍".to_owned(),
+            },
+            Command::Unreact {
+                id: 1,
+                emoji: "👍".to_owned(),
+            },
+            Command::Delete(1),
+            Command::Typing {
+                target: None,
+                typing: true,
+            },
+        ];
+        for command in commands {
+            servers[0].handle_message(olly, message(command));
+        }
+        assert!(links[0].ends[0].sent().is_empty());
+        assert!(broadcasts[1].try_recv().is_err());
+        assert!(lines(&mut bob_recv).is_empty());
+    }
+
+    #[test]
+    fn clients_see_users_on_other_servers_change() {
+        let (mut servers, mut broadcasts) = network(&["floor1.lan", "floor2.lan", "floor3.lan"]);
+        let (olly, _) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        let (bob, _) = connect(&mut servers[1], "10.0.2.2:5000");
+        servers[1].handle_message(bob, message(Command::Nick("bob".to_owned())));
+        let mut links = vec![link(&mut servers, 0, "10.0.1.1:40000", 1, "10.0.2.1:3000")];
+        settle(&mut servers, &mut links);
+        while broadcasts[1].try_recv().is_ok() {}
+
+        servers[0].handle_message(olly, message(Command::Nick("oliver".to_owned())));
+        settle(&mut servers, &mut links);
+        assert_eq!(":olly NICK oliver\r\n", broadcasts[1].try_recv().unwrap());
+
+        // A user on floor3.lan that registered earlier takes the nick from oliver when
+        // floor3.lan joins the network.
+        let (early, _) = connect(&mut servers[2], "10.0.3.2:5000");
+        servers[2].clients.get_mut(&early).unwrap().connected_at = std::time::UNIX_EPOCH;
+        servers[2].handle_message(early, message(Command::Nick("oliver".to_owned())));
+        links.push(link(&mut servers, 2, "10.0.3.1:40000", 1, "10.0.2.1:3000"));
+        settle(&mut servers, &mut links);
+        assert_eq!(
+            ":oliver NICK oliver-floor1\r\n",
+            broadcasts[1].try_recv().unwrap()
+        );
+
+        servers[0].handle_message(olly, message(Command::Quit));
+        settle(&mut servers, &mut links);
+        assert_eq!(":oliver-floor1 QUIT\r\n", broadcasts[1].try_recv().unwrap());
+    }
+
+    #[test]
+    fn nick_collisions_are_resolved_the_same_way_on_both_sides() {
+        let (mut servers, _broadcasts) = network(&["floor1.lan", "floor2.lan"]);
+        // The first olly registered no later than the second, and ties go to floor1.lan.
+        let (first, mut first_recv) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(first, message(Command::Nick("olly".to_owned())));
+        let (second, mut second_recv) = connect(&mut servers[1], "10.0.2.2:5000");
+        servers[1].handle_message(second, message(Command::Nick("olly".to_owned())));
+
+        let mut links = [link(&mut servers, 1, "10.0.2.1:40000", 0, "10.0.1.1:3000")];
+        settle(&mut servers, &mut links);
+
+        assert!(lines(&mut first_recv).is_empty());
+        assert_eq!(vec![":olly NICK olly-floor2\r\n"], lines(&mut second_recv));
+        let expected = vec![
+            "NAMREPLY olly H\r\n".to_owned(),
+            "NAMREPLY olly-floor2 H\r\n".to_owned(),
+            "ENDOFNAMES\r\n".to_owned(),
+        ];
+        assert_eq!(expected, names(&mut servers[0], first));
+        assert_eq!(expected, names(&mut servers[1], second));
+
+        let msg = message(Command::Notice {
+            target: Some("olly-floor2".to_owned()),
+            text: "you were renamed".to_owned(),
+        });
+        servers[0].handle_message(first, msg);
+        settle(&mut servers, &mut links);
+        assert_eq!(
+            vec![":olly NOTICE olly-floor2 :you were renamed\r\n"],
+            lines(&mut second_recv)
+        );
+    }
+
+    #[test]
+    fn nick_changes_that_collide_are_resolved_the_same_way_on_both_sides() {
+        let (mut servers, _broadcasts) = network(&["floor1.lan", "floor2.lan"]);
+        let (olly, mut olly_recv) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        let (bob, mut bob_recv) = connect(&mut servers[1], "10.0.2.2:5000");
+        servers[1].handle_message(bob, message(Command::Nick("bob".to_owned())));
+        let mut links = [link(&mut servers, 1, "10.0.2.1:40000", 0, "10.0.1.1:3000")];
+        settle(&mut servers, &mut links);
+        lines(&mut olly_recv);
+        lines(&mut bob_recv);
+
+        // Both take the same nick before either server hears of the other's change. olly
+        // registered no later than bob, and ties go to floor1.lan.
+        servers[0].handle_message(olly, message(Command::Nick("sam".to_owned())));
+        servers[1].handle_message(bob, message(Command::Nick("sam".to_owned())));
+        settle(&mut servers, &mut links);
+
+        assert!(lines(&mut olly_recv).is_empty());
+        assert_eq!(vec![":sam NICK sam-floor2\r\n"], lines(&mut bob_recv));
+        let expected = vec![
+            "NAMREPLY sam H\r\n".to_owned(),
+            "NAMREPLY sam-floor2 H\r\n".to_owned(),
+            "ENDOFNAMES\r\n".to_owned(),
+        ];
+        assert_eq!(expected, names(&mut servers[0], olly));
+        assert_eq!(expected, names(&mut servers[1], bob));
+    }
+
+    #[test]
+    fn link_that_is_not_keeping_up_is_dropped() {
+        let (mut servers, mut broadcasts) = network(&["floor1.lan", "floor2.lan"]);
+        let (olly, _) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        let mut links = [link(&mut servers, 0, "10.0.1.1:40000", 1, "10.0.2.1:3000")];
+        settle(&mut servers, &mut links);
+        while broadcasts[0].try_recv().is_ok() {}
+
+        // floor2.lan stops reading from the link until the queue is full.
+        let floor2 = links[0].ends[0].addr;
+        for _ in 0..LINK_QUEUE {
+            servers[0].send_to_links(":olly AWAY\r\n", None);
+        }
+        assert!(servers[0].links.get(floor2).is_some());
+        servers[0].handle_message(olly, message(Command::Nick("oliver".to_owned())));
+        assert!(servers[0].links.get(floor2).is_none());
+        assert!(broadcasts[0].try_recv().unwrap().contains("Netsplit"));
+
+        // Linking again brings floor2.lan up to date.
+        servers[1].disconnect(links[0].ends[1].addr);
+        let mut links = [link(&mut servers, 0, "10.0.1.1:40001", 1, "10.0.2.1:3000")];
+        settle(&mut servers, &mut links);
+        let users: Vec<_> = servers[1]
+            .links
+            .users()
+            .map(|user| user.nick.clone())
+            .collect();
+        assert_eq!(vec!["oliver"], users);
+    }
+
+    #[test]
+    fn netsplit_removes_users_behind_the_lost_link() {
+        let (mut servers, mut broadcasts) = network(&["floor1.lan", "floor2.lan", "floor3.lan"]);
+        let (olly, _) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        let (carol, _) = connect(&mut servers[2], "10.0.3.2:5000");
+        servers[2].handle_message(carol, message(Command::Nick("carol".to_owned())));
+
+        // floor2.lan is linked to both of the others, which learn of each other's users through
+        // it.
+        let mut links = [
+            link(&mut servers, 0, "10.0.1.1:40000", 1, "10.0.2.1:3000"),
+            link(&mut servers, 2, "10.0.3.1:40000", 1, "10.0.2.1:3000"),
+        ];
+        settle(&mut servers, &mut links);
+        let expected = vec![
+            "NAMREPLY carol H\r\n".to_owned(),
+            "NAMREPLY olly H\r\n".to_owned(),
+            "ENDOFNAMES\r\n".to_owned(),
+        ];
+        assert_eq!(expected, names(&mut servers[0], olly));
+
+        // floor2.lan loses its link to floor3.lan.
+        let lost = links[1].ends[1].addr;
+        servers[1].disconnect(lost);
+        settle(&mut servers, &mut links[..1]);
+
+        assert_eq!(
+            ":floor2.lan NOTICE :Netsplit: lost the link to floor3.lan, 1 users left\r\n",
+            broadcasts[1].try_recv().unwrap()
+        );
+        let expected = vec![
+            "NAMREPLY olly H\r\n".to_owned(),
+            "ENDOFNAMES\r\n".to_owned(),
+        ];
+        assert_eq!(expected, names(&mut servers[0], olly));
+        // The nick of the user that left is free again.
+        let (other, _) = connect(&mut servers[0], "10.0.1.3:5000");
+        assert!(matches!(
+            servers[0].handle_message(other, message(Command::Nick("carol".to_owned()))),
+            Response::Reply(_)
+        ));
+        let remaining: Vec<_> = servers[1].links.users().map(|u| u.nick.as_str()).collect();
+        assert_eq!(vec!["olly"], remaining);
+    }
+
+    #[test]
+    fn links_must_authenticate() {
+        let (mut servers, _broadcasts) = network(&["floor1.lan", "floor2.lan"]);
+        let server = |name: &str, password: &str| {
+            message(Command::Server {
+                name: name.to_owned(),
+                password: password.to_owned(),
+            })
+        };
+
+        let (wrong_password, _) = connect(&mut servers[0], "10.0.2.1:40000");
+        assert!(matches!(
+            servers[0].handle_message(wrong_password, server("floor2.lan", "guess")),
+            Response::Refuse(_)
+        ));
+        let (unknown, _) = connect(&mut servers[0], "10.0.2.1:40001");
+        assert!(matches!(
+            servers[0].handle_message(unknown, server("floor9.lan", "secret")),
+            Response::Refuse(_)
+        ));
+
+        let (olly, _) = connect(&mut servers[0], "10.0.1.2:5000");
+        servers[0].handle_message(olly, message(Command::Nick("olly".to_owned())));
+        assert!(matches!(
+            servers[0].handle_message(olly, server("floor2.lan", "secret")),
+            Response::Reply(_)
+        ));
+
+        let (linked, _) = connect(&mut servers[0], "10.0.2.1:40002");
+        match servers[0].handle_message(linked, server("floor2.lan", "secret")) {
+            Response::Linked(mut lines) => {
+                let mut sent = Vec::new();
+                while let Ok(line) = lines.try_recv() {
+                    sent.push(line);
+                }
+                assert_eq!(
+                    vec![
+                        "SERVER floor1.lan secret\r\n".to_owned(),
+                        format!(
+                            ":floor1.lan INTRODUCE olly {}\r\n",
+                            servers[0].clients[&olly].connected_secs()
+                        ),
+                    ],
+                    sent
+                );
             }
+            other => panic!("unexpected response: {:?}", other),
         }
+        // The server is only linked once.
+        let (again, _) = connect(&mut servers[0], "10.0.2.1:40003");
+        assert!(matches!(
+            servers[0].handle_message(again, server("floor2.lan", "secret")),
+            Response::Refuse(_)
+        ));
     }
 }
@@ -0,0 +1,143 @@
+//! Files being sent between clients through the server, see [`protocol::file`].
+use std::{collections::HashMap, net::SocketAddr};
+
+/// The file transfers in progress, keyed by the id given to the transfer when it was offered.
+#[derive(Debug)]
+pub(crate) struct Transfers {
+    transfers: HashMap<u64, Transfer>,
+    next_id: u64,
+}
+
+/// A file offered by one client to another.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Transfer {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub size: u64,
+    /// The hash given in the offer, which must match the hash sent once the file is complete.
+    pub hash: String,
+    /// Whether the file is sent over a direct connection between the clients, in which case the
+    /// server only relays the offer and the answer to it.
+    pub direct: bool,
+    /// Whether the recipient has accepted the offer, chunks are only relayed once it has.
+    pub accepted: bool,
+    /// The number of bytes of the file relayed so far.
+    pub relayed: u64,
+}
+
+impl Transfers {
+    pub fn new() -> Transfers {
+        Transfers {
+            transfers: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Records an offered file, returning the id given to the transfer.
+    pub fn offer(
+        &mut self,
+        from: SocketAddr,
+        to: SocketAddr,
+        size: u64,
+        hash: String,
+        direct: bool,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.transfers.insert(
+            id,
+            Transfer {
+                from,
+                to,
+                size,
+                hash,
+                direct,
+                accepted: false,
+                relayed: 0,
+            },
+        );
+        id
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Transfer> {
+        self.transfers.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Transfer> {
+        self.transfers.remove(&id)
+    }
+
+    /// The number of files the client at `addr` has offered that are still in progress.
+    pub fn sending(&self, addr: SocketAddr) -> usize {
+        self.transfers.values().filter(|t| t.from == addr).count()
+    }
+
+    /// Removes the transfers to or from the client at `addr`, returning their ids along with the
+    /// address of the other client.
+    pub fn remove_client(&mut self, addr: SocketAddr) -> Vec<(u64, SocketAddr)> {
+        let mut removed = Vec::new();
+        self.transfers.retain(|&id, transfer| {
+            let other = match addr {
+                addr if addr == transfer.from => transfer.to,
+                addr if addr == transfer.to => transfer.from,
+                _ => return true,
+            };
+            removed.push((id, other));
+            false
+        });
+        removed.sort();
+        removed
+    }
+}
+
+impl Transfer {
+    /// Checks that a chunk of `len` bytes at `offset` follows the chunks already relayed without
+    /// going past the offered size, and counts it as relayed.
+    pub fn relay(&mut self, offset: u64, len: u64) -> Result<(), String> {
+        if !self.accepted {
+            return Err("The file has not been accepted".to_owned());
+        }
+        if offset != self.relayed {
+            return Err(format!("Expected a chunk at offset {}", self.relayed));
+        }
+        if self.size - self.relayed < len {
+            return Err(format!(
+                "The file is larger than the {} bytes offered",
+                self.size
+            ));
+        }
+        self.relayed += len;
+        Ok(())
+    }
+
+    /// Whether every byte of the file has been relayed.
+    pub fn is_complete(&self) -> bool {
+        self.accepted && self.relayed == self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_must_be_in_order_and_within_the_offer() {
+        let olly = "127.0.0.1:5000".parse().unwrap();
+        let bob = "127.0.0.1:5001".parse().unwrap();
+        let mut transfers = Transfers::new();
+        let id = transfers.offer(olly, bob, 10, "hash".to_owned(), false);
+        let transfer = transfers.get_mut(id).unwrap();
+
+        assert!(transfer.relay(0, 4).is_err());
+        transfer.accepted = true;
+        assert_eq!(Ok(()), transfer.relay(0, 4));
+        assert!(transfer.relay(0, 4).is_err());
+        assert!(transfer.relay(4, 7).is_err());
+        assert_eq!(Ok(()), transfer.relay(4, 6));
+        assert!(transfer.is_complete());
+
+        assert_eq!(1, transfers.sending(olly));
+        assert_eq!(vec![(id, olly)], transfers.remove_client(bob));
+        assert_eq!(0, transfers.sending(olly));
+    }
+}